@@ -0,0 +1,37 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+
+use companion::Client;
+
+// Descriptors are passed only over UNIX sockets, run with RUST_COMPANION=unix:
+fn main() {
+    let client = Client::connect().unwrap();
+
+    let (read, write) = nix::unistd::pipe().unwrap();
+    let mut read = unsafe { File::from_raw_fd(read) };
+    let write = unsafe { File::from_raw_fd(write) };
+
+    // hand the write end over to the companion
+    client.set_fd("pipe", write.as_raw_fd()).unwrap();
+    drop(write);
+
+    // and get a copy of it back
+    let mut write = File::from(
+        client
+            .get_fd("pipe")
+            .unwrap()
+            .expect("no descriptor stored"),
+    );
+    write.write_all(b"hello through the companion").unwrap();
+    drop(write);
+
+    // the companion closes its copy on shutdown
+    client.shutdown().unwrap();
+
+    let mut text = String::new();
+    read.read_to_string(&mut text).unwrap();
+    println!("{text}");
+}
@@ -0,0 +1,18 @@
+use companion::{pid_path, Response, Server, Task};
+
+fn main() {
+    Server::builder()
+        .pid_file(pid_path())
+        .handler("reverse", |_, payload| {
+            Response::Bytes(payload.iter().rev().copied().collect())
+        })
+        .handler("visit", |state, payload| {
+            match std::str::from_utf8(payload) {
+                Ok(page) => state.apply(Task::Incr(format!("visits/{page}").into(), 1)),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        })
+        .build()
+        .run()
+        .unwrap();
+}
@@ -3,5 +3,5 @@ use companion::{launch, pid_path};
 
 fn main() {
     let pid_path = pid_path();
-    launch(&pid_path)
+    launch(&pid_path).unwrap()
 }
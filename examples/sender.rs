@@ -1,22 +1,24 @@
-#![allow(unused_imports)]
-use std::{fs, net::UdpSocket, path::PathBuf, time::Duration};
-
-use serde::{Deserialize, Serialize};
-
-use companion::{companion_addr, Response, Task};
+use companion::Client;
 
 fn main() {
-    let addr = companion_addr();
+    let client = Client::connect().unwrap();
 
-    let socket = UdpSocket::bind("[::]:0").unwrap();
-    socket.connect(addr).unwrap();
+    client.set("greeting", "hello").unwrap();
+    println!("{:?}", client.list().unwrap());
+    println!("{:?}", client.get("greeting").unwrap());
 
-    let mut buf = [0; 65507];
+    let swapped = client.compare_and_swap("greeting", Some("hello"), "hi");
+    println!("{:?}", swapped.unwrap());
+    println!("{:?}", client.incr("counter", 1).unwrap());
+    println!("{:?}", client.sum(&[1, 2, 3]).unwrap());
 
-    socket.send(&Task::List.as_bytes()).unwrap();
+    println!("{:?}", client.delete_prefix("greet").unwrap());
 
-    let (len, _src) = socket.recv_from(&mut buf).unwrap();
-    let resp = Response::from(&buf[..len]);
+    let other = Client::connect().unwrap().with_namespace("other");
+    other.set("greeting", "hello").unwrap();
+    println!("{:?}", client.get("greeting").unwrap());
+    println!("{:?}", client.namespaces().unwrap());
 
-    println!("{resp:?}")
+    client.set_value("point", &(1, 2)).unwrap();
+    println!("{:?}", client.get_value::<(i32, i32)>("point").unwrap());
 }
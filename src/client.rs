@@ -0,0 +1,457 @@
+//! Client side of the companion protocol.
+//!
+//! ```no_run
+//! let client = companion::Client::connect()?;
+//! client.set("answer", "42")?;
+//! assert_eq!(client.get("answer")?.as_deref(), Some("42"));
+//! # Ok::<(), companion::Error>(())
+//! ```
+//!
+use std::{
+    cell::{Cell, RefCell},
+    os::unix::io::{OwnedFd, RawFd},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    companion_address, default_namespace, Address, Codec, Connection, Envelope, Error, Response,
+    Result, Stats, Task, DEFAULT_NAMESPACE, PROTOCOL_VERSION,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_RETRIES: usize = 3;
+
+/// Connection to a running companion.
+///
+/// Every request carries a fresh id and replies with any other id are
+/// discarded, so a reply arriving late can not be mistaken for the answer to
+/// a later request. Over stream transports the connection is also reopened
+/// when a request fails. The atomic tasks are never resent, see
+/// [`Task::is_retryable`].
+#[derive(Debug)]
+pub struct Client {
+    addr: Address,
+    connection: RefCell<Connection>,
+    next_id: Cell<u64>,
+    codec: Codec,
+    timeout: Duration,
+    retries: usize,
+    namespace: String,
+}
+
+impl Client {
+    /// Connect to the companion at `companion_addr()` using the
+    /// [`default_namespace`].
+    pub fn connect() -> Result<Self> {
+        Ok(Self::connect_to(&companion_address())?.with_namespace(default_namespace()))
+    }
+
+    pub fn connect_to(addr: &Address) -> Result<Self> {
+        let connection = Connection::connect(addr)?;
+        connection.set_timeout(Some(DEFAULT_TIMEOUT))?;
+        Ok(Self {
+            addr: addr.clone(),
+            connection: RefCell::new(connection),
+            next_id: Cell::new(1),
+            codec: Codec::default(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            namespace: DEFAULT_NAMESPACE.into(),
+        })
+    }
+
+    /// Time to wait for each reply before retrying.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.connection.borrow().set_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    /// Number of times a request is resent when no reply arrives, atomic
+    /// tasks are sent once.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wire format of the requests, the companion replies in the same one.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Namespace all tasks sent by this client apply to.
+    pub fn with_namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Send a task and wait for the reply.
+    pub fn request(&self, task: &Task) -> Result<Response> {
+        let (response, _) = self.request_with_fds(task, &[])?;
+        Ok(response)
+    }
+
+    /// Send a task with file descriptors attached and wait for the reply
+    /// along with any descriptors attached to it.
+    ///
+    /// A [`Response::Error`] reply is returned as [`Error::Companion`].
+    pub fn request_with_fds(&self, task: &Task, fds: &[RawFd]) -> Result<(Response, Vec<OwnedFd>)> {
+        let id = self.next_id();
+        // the handshake, handlers and shutdown are served by the companion
+        // itself, not a namespace
+        let scoped = !matches!(task, Task::Hello { .. } | Task::Call(..) | Task::Shutdown);
+        let bytes = if self.namespace == DEFAULT_NAMESPACE || !scoped {
+            self.codec.encode(&Envelope::new(id, task))?
+        } else {
+            let task = task.clone().namespaced(self.namespace.as_str());
+            self.codec.encode(&Envelope::new(id, task))?
+        };
+
+        let retries = if task.is_retryable() { self.retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            match self.exchange(&bytes, fds, id) {
+                Ok((response, fds)) => {
+                    return match response {
+                        Response::Error(message) => Err(Error::Companion(message)),
+                        response => Ok((response, fds)),
+                    };
+                }
+                Err(err) => {
+                    let stream = self.addr.is_stream();
+                    let retry = match err {
+                        Error::Timeout => true,
+                        // the companion may have restarted
+                        Error::Io(_) => stream,
+                        _ => false,
+                    };
+                    if !retry || attempt >= retries {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    if stream {
+                        self.reconnect()?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send the request once and wait for the reply carrying `id`.
+    fn exchange(&self, bytes: &[u8], fds: &[RawFd], id: u64) -> Result<(Response, Vec<OwnedFd>)> {
+        let connection = self.connection.borrow();
+        connection.send(bytes, fds)?;
+        loop {
+            let (buf, fds) = connection.recv()?;
+            let reply: Envelope<Response> = self.codec.decode(&buf)?;
+            if reply.id == id {
+                return Ok((reply.body, fds));
+            }
+            // stale reply to an earlier attempt, its descriptors are dropped
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+        id
+    }
+
+    fn reconnect(&self) -> Result<()> {
+        let connection = Connection::connect(&self.addr)?;
+        connection.set_timeout(Some(self.timeout))?;
+        *self.connection.borrow_mut() = connection;
+        Ok(())
+    }
+
+    /// Protocol version of the companion, an [`Error::Companion`] when it
+    /// does not speak [`PROTOCOL_VERSION`].
+    pub fn hello(&self) -> Result<u32> {
+        match self.request(&Task::Hello {
+            version: PROTOCOL_VERSION,
+        })? {
+            Response::Hello { version } => Ok(version),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Invoke a handler registered with [`crate::Server::builder`].
+    ///
+    /// Handlers see the whole storage regardless of the namespace of the
+    /// client.
+    pub fn call(&self, name: &str, payload: &[u8]) -> Result<Response> {
+        self.request(&Task::Call(name.into(), payload.to_vec()))
+    }
+
+    /// Get a value as a string, binary values are accepted when they are
+    /// valid UTF-8.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.request(&Task::Get(key.into()))? {
+            Response::String(value) => Ok(Some(value)),
+            Response::Bytes(value) => match String::from_utf8(value) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) => Err(Error::Unexpected(Response::Bytes(err.into_bytes()))),
+            },
+            Response::NotFound => Ok(None),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        match self.request(&Task::Set(key.into(), value.into()))? {
+            Response::Ok => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Store a value that expires after `ttl`.
+    pub fn set_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        match self.request(&Task::SetWithTtl(key.into(), value.into(), ttl))? {
+            Response::Ok => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Keep a stored value for `ttl` from now. Returns whether the value
+    /// exists.
+    pub fn touch(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.bool(&Task::Touch(key.into(), ttl))
+    }
+
+    /// Get a value as raw bytes, string values are returned as UTF-8.
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.request(&Task::Get(key.into()))? {
+            Response::Bytes(value) => Ok(Some(value)),
+            Response::String(value) => Ok(Some(value.into_bytes())),
+            Response::NotFound => Ok(None),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    pub fn set_bytes(&self, key: &str, value: &[u8]) -> Result<()> {
+        match self.request(&Task::SetBytes(key.into(), value.to_vec()))? {
+            Response::Ok => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Store binary data that expires after `ttl`.
+    pub fn set_bytes_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        match self.request(&Task::SetBytesWithTtl(key.into(), value.to_vec(), ttl))? {
+            Response::Ok => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Get a value stored with [`Client::set_value`].
+    pub fn get_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.get_bytes(key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store any serializable value, encoded with bincode.
+    pub fn set_value<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<()> {
+        self.set_bytes(key, &bincode::serialize(value)?)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        match self.request(&Task::List)? {
+            Response::List(keys) => Ok(keys),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Namespaces holding any data in the companion.
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        match self.request(&Task::Namespaces)? {
+            Response::List(names) => Ok(names),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Storage statistics of the companion.
+    pub fn stats(&self) -> Result<Stats> {
+        match self.request(&Task::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Remove the value and file descriptor stored by name, returns how many
+    /// entries were removed.
+    pub fn delete(&self, key: &str) -> Result<u64> {
+        self.count(&Task::Delete(key.into()))
+    }
+
+    /// Remove everything stored under names starting with `prefix`.
+    pub fn delete_prefix(&self, prefix: &str) -> Result<u64> {
+        self.count(&Task::DeletePrefix(prefix.into()))
+    }
+
+    /// Remove everything stored in the namespace of the client. Values in
+    /// other namespaces are kept, see [`Client::namespaces`].
+    pub fn clear(&self) -> Result<u64> {
+        self.count(&Task::Clear)
+    }
+
+    /// Atomically add `delta` to the integer stored by name and return the
+    /// new value, a missing name starts from 0.
+    pub fn incr(&self, key: &str, delta: i64) -> Result<i64> {
+        match self.request(&Task::Incr(key.into(), delta))? {
+            Response::Integer(value) => Ok(value),
+            Response::Overflow => Err(Error::Overflow),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Atomically replace the value if it equals `expected`, `None` expects
+    /// the name to be absent. Returns whether the value was replaced.
+    pub fn compare_and_swap(&self, key: &str, expected: Option<&str>, value: &str) -> Result<bool> {
+        self.bool(&Task::CompareAndSwap(
+            key.into(),
+            expected.map(Into::into),
+            value.into(),
+        ))
+    }
+
+    /// Store the value unless the name is taken. Returns whether it was stored.
+    pub fn set_if_absent(&self, key: &str, value: &str) -> Result<bool> {
+        self.bool(&Task::SetIfAbsent(key.into(), value.into()))
+    }
+
+    /// Sum computed by the companion, [`Error::Overflow`] when it does not
+    /// fit in `i64`.
+    pub fn sum(&self, values: &[i64]) -> Result<i64> {
+        match self.request(&Task::Sum(values.to_vec()))? {
+            Response::Integer(sum) => Ok(sum),
+            Response::Overflow => Err(Error::Overflow),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    pub fn min(&self, values: &[i64]) -> Result<Option<i64>> {
+        self.integer(&Task::Min(values.to_vec()))
+    }
+
+    pub fn max(&self, values: &[i64]) -> Result<Option<i64>> {
+        self.integer(&Task::Max(values.to_vec()))
+    }
+
+    pub fn mean(&self, values: &[i64]) -> Result<Option<f64>> {
+        match self.request(&Task::Mean(values.to_vec()))? {
+            Response::Float(mean) => Ok(Some(mean)),
+            Response::NotFound => Ok(None),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Ask the companion to exit. Returns once the companion acknowledged,
+    /// it finishes the requests in flight and cleans up afterwards.
+    pub fn shutdown(&self) -> Result<()> {
+        match self.request(&Task::Shutdown)? {
+            Response::Ok => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Hand a file descriptor over to the companion, UNIX transport only.
+    pub fn set_fd(&self, key: &str, fd: RawFd) -> Result<()> {
+        match self.request_with_fds(&Task::SetFd(key.into()), &[fd])? {
+            (Response::Ok, _) => Ok(()),
+            (other, _) => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// Get a copy of a file descriptor stored in the companion.
+    pub fn get_fd(&self, key: &str) -> Result<Option<OwnedFd>> {
+        match self.request_with_fds(&Task::GetFd(key.into()), &[])? {
+            (Response::Fd, mut fds) => match fds.pop() {
+                Some(fd) => Ok(Some(fd)),
+                None => Err(Error::Unexpected(Response::Fd)),
+            },
+            (Response::NotFound, _) => Ok(None),
+            (other, _) => Err(Error::Unexpected(other)),
+        }
+    }
+
+    fn count(&self, task: &Task) -> Result<u64> {
+        match self.request(task)? {
+            Response::Count(count) => Ok(count),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    fn bool(&self, task: &Task) -> Result<bool> {
+        match self.request(task)? {
+            Response::Bool(value) => Ok(value),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    fn integer(&self, task: &Task) -> Result<Option<i64>> {
+        match self.request(task)? {
+            Response::Integer(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::net::UnixDatagram};
+
+    use super::*;
+    use crate::MAX_DATAGRAM;
+
+    /// Requests the client sends to a companion that never answers.
+    fn sent(send: impl FnOnce(&Client) -> Result<()>) -> usize {
+        let dir = std::env::temp_dir().join(format!(
+            "companion-retries-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("companion.sock");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        let addr = Address::parse(&format!("unix:{}", path.display()));
+        let client = Client::connect_to(&addr)
+            .unwrap()
+            .with_timeout(Duration::from_millis(20))
+            .unwrap()
+            .with_retries(2);
+        assert!(matches!(send(&client), Err(Error::Timeout)));
+
+        listener.set_nonblocking(true).unwrap();
+        let mut buf = [0; MAX_DATAGRAM];
+        let count = std::iter::from_fn(|| listener.recv(&mut buf).ok()).count();
+        fs::remove_dir_all(dir).unwrap();
+        count
+    }
+
+    #[test]
+    fn lost_replies_are_retried() {
+        assert_eq!(sent(|client| client.set("a", "1")), 3);
+    }
+
+    #[test]
+    fn atomic_tasks_are_sent_once() {
+        assert_eq!(sent(|client| client.incr("a", 1).map(drop)), 1);
+        assert_eq!(
+            sent(|client| client.compare_and_swap("a", None, "1").map(drop)),
+            1
+        );
+        let namespaced = Task::SetIfAbsent("a".into(), "1".into()).namespaced("other");
+        assert_eq!(sent(|client| client.request(&namespaced).map(drop)), 1);
+    }
+}
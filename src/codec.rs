@@ -0,0 +1,103 @@
+//! Wire formats of the companion protocol.
+//!
+//! Bincode is always available and used by [`crate::Client`] by default.
+//! The `json` and `msgpack` features add formats that tools written in other
+//! languages can speak. The companion recognizes the format of each request
+//! by its first byte and replies in the same format.
+//!
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(feature = "json", feature = "msgpack"))]
+use crate::Error;
+use crate::Result;
+
+/// Encoding of messages on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Bincode,
+    // Envelope encoded as a JSON object
+    #[cfg(feature = "json")]
+    Json,
+    // Envelope encoded as a MessagePack array or map
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    /// Format of an encoded envelope.
+    ///
+    /// Bincode envelopes start with the little endian protocol version, so
+    /// their first byte never collides with a JSON object or a MessagePack
+    /// array or map.
+    pub fn detect(bytes: &[u8]) -> Self {
+        match bytes.first() {
+            #[cfg(feature = "json")]
+            Some(b'{') => Codec::Json,
+            #[cfg(feature = "msgpack")]
+            Some(0x80..=0x9f | 0xdc..=0xdf) => Codec::MessagePack,
+            _ => Codec::Bincode,
+        }
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+            #[cfg(feature = "json")]
+            Codec::Json => serde_json::to_vec(value).map_err(|err| Error::Format(err.to_string())),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|err| Error::Format(err.to_string()))
+            }
+        }
+    }
+
+    pub fn decode<'a, T: Deserialize<'a>>(self, bytes: &'a [u8]) -> Result<T> {
+        match self {
+            Codec::Bincode => Ok(bincode::deserialize(bytes)?),
+            #[cfg(feature = "json")]
+            Codec::Json => {
+                serde_json::from_slice(bytes).map_err(|err| Error::Format(err.to_string()))
+            }
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|err| Error::Format(err.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Envelope, Task};
+
+    fn hello() -> Envelope<Task<'static>> {
+        Envelope::new(7, Task::Hello { version: 3 })
+    }
+
+    #[test]
+    fn bincode_is_the_fallback() {
+        let bytes = Codec::Bincode.encode(&hello()).unwrap();
+        assert_eq!(Codec::detect(&bytes), Codec::Bincode);
+        assert_eq!(Codec::detect(&[]), Codec::Bincode);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_is_detected() {
+        let bytes = Codec::Json.encode(&hello()).unwrap();
+        assert_eq!(Codec::detect(&bytes), Codec::Json);
+        let envelope: Envelope<Task> = Codec::Json.decode(&bytes).unwrap();
+        assert_eq!(envelope.id, 7);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_is_detected() {
+        let bytes = Codec::MessagePack.encode(&hello()).unwrap();
+        assert_eq!(Codec::detect(&bytes), Codec::MessagePack);
+        let envelope: Envelope<Task> = Codec::MessagePack.decode(&bytes).unwrap();
+        assert_eq!(envelope.id, 7);
+    }
+}
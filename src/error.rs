@@ -0,0 +1,61 @@
+use std::{fmt, io};
+
+use crate::Response;
+
+/// Errors returned when talking to the companion.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // No reply within the timeout after all retries
+    Timeout,
+    Encoding(bincode::Error),
+    // Message in a wire format other than bincode could not be encoded or decoded
+    Format(String),
+    // Reply does not match the request
+    Unexpected(Response),
+    // Companion answered with `Response::Error`
+    Companion(String),
+    // Companion answered with `Response::Overflow`
+    Overflow,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "companion i/o error: {err}"),
+            Error::Timeout => write!(f, "companion did not reply in time"),
+            Error::Encoding(err) => write!(f, "companion protocol error: {err}"),
+            Error::Format(message) => write!(f, "companion protocol error: {message}"),
+            Error::Unexpected(response) => write!(f, "unexpected companion reply: {response:?}"),
+            Error::Companion(message) => write!(f, "companion error: {message}"),
+            Error::Overflow => write!(f, "companion arithmetic overflow"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Encoding(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Encoding(err)
+    }
+}
@@ -1,39 +1,85 @@
-#![allow(unused_imports)]
 #![allow(unreachable_code)]
 #![allow(unused_variables)]
 //! This crate implements a minimal abstraction over Udp/UNIX domain sockets for
 //! the purpose of IPC.  It lets you send both file handles and rust objects
 //! between processes.
 //!
+//! The companion address is taken from the `RUST_COMPANION` environment
+//! variable, see [`Address`] for the supported schemes. It defaults to UDP on
+//! `[::]:2000`. Values larger than one datagram need a stream scheme,
+//! `tcp:` or `unix-stream:`. Besides bincode, the companion understands
+//! JSON and MessagePack requests when built with the `json` and `msgpack`
+//! features, see [`Codec`].
+//!
+//! Values are kept in memory unless `RUST_COMPANION_SNAPSHOT` names a
+//! snapshot file, see [`snapshot_path`]. Their number and size can be
+//! bounded, see [`capacity`].
+//!
+//! [`bootstrap`] returns once the companion it spawns serves, or with the
+//! error that kept it from starting. The companion exits with everything
+//! cleaned up after [`DEFAULT_IDLE_TIMEOUT`] without requests, see
+//! [`idle_timeout`].
+//!
 use std::{
-    collections::HashMap,
-    env, fs,
-    net::UdpSocket,
-    os::unix::{
-        io::{FromRawFd, IntoRawFd},
-        net::UnixListener,
-    },
+    env, fs, io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     path::{Path, PathBuf},
     process::Stdio,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use sysinfo::{Pid, PidExt, SystemExt};
-
+#[cfg(feature = "log")]
+extern crate logging as log;
 #[cfg(feature = "log")]
 use log::*;
 #[cfg(feature = "log")]
 use syslog::{BasicLogger, Facility, Formatter3164};
 
-use serde::{Deserialize, Serialize};
+mod client;
+mod codec;
+mod error;
+mod process;
+mod protocol;
+mod server;
+mod state;
+mod transport;
+use process::Record;
+
+pub use client::Client;
+pub use codec::Codec;
+pub use error::{Error, Result};
+pub use protocol::{Envelope, Response, Stats, Task, PROTOCOL_VERSION};
+pub use server::{Builder, Handler, Server, ServerHandle};
+pub use state::{Capacity, Namespace, State, Value, DEFAULT_NAMESPACE};
+pub use transport::{
+    runtime_dir, socket_path, Address, Connection, Listener, Peer, Socket, Stream, MAX_DATAGRAM,
+};
 
 pub(crate) const ENV_VAR: &str = "RUST_COMPANION";
+pub(crate) const NAMESPACE_ENV_VAR: &str = "RUST_COMPANION_NAMESPACE";
+pub(crate) const SNAPSHOT_ENV_VAR: &str = "RUST_COMPANION_SNAPSHOT";
+pub(crate) const MAX_ENTRIES_ENV_VAR: &str = "RUST_COMPANION_MAX_ENTRIES";
+pub(crate) const MAX_BYTES_ENV_VAR: &str = "RUST_COMPANION_MAX_BYTES";
 pub(crate) const PROGRAM_NAME: &str = "rust-companion";
+pub(crate) const LOCK_FILE: &str = "companion.lock";
+pub(crate) const IDLE_TIMEOUT_ENV_VAR: &str = "RUST_COMPANION_IDLE_TIMEOUT";
+pub(crate) const READY_FD_ENV_VAR: &str = "RUST_COMPANION_READY_FD";
 
-#[cfg(feature = "log")]
-fn setup_logger() {
-    use companion::PROGRAM_NAME;
+/// Idle timeout of the companion spawned by [`bootstrap`] unless
+/// `RUST_COMPANION_IDLE_TIMEOUT` says otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How long an incompatible companion is given to exit.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a running companion is given to answer the handshake.
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long [`bootstrap`] waits for the spawned companion to bind.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
 
+#[cfg(feature = "log")]
+pub(crate) fn setup_logger() {
     let formatter = Formatter3164 {
         facility: Facility::LOG_USER,
         hostname: None,
@@ -41,47 +87,12 @@ fn setup_logger() {
         pid: 0,
     };
 
-    let logger = syslog::unix(formatter).expect("could not connect to syslog");
-    log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(LevelFilter::Info))
-        .expect("could not register logger");
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Response {
-    String(String),
-    List(Vec<String>),
-    Ok,
-    NotFound,
-}
-
-impl Response {
-    pub fn as_bytes(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
-    }
-}
-
-impl From<&[u8]> for Response {
-    fn from(bytes: &[u8]) -> Self {
-        bincode::deserialize(bytes).unwrap()
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Task<'a> {
-    // Get data by name
-    Get(&'a str),
-    // Store data by name
-    Set(&'a str, &'a str),
-    // List stored names
-    List,
-    Sum(Vec<i64>),
-    Shutdown,
-}
-
-impl<'a> Task<'a> {
-    pub fn as_bytes(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+    // serving goes on without logs when syslog is unavailable or another
+    // logger is registered already
+    if let Ok(logger) = syslog::unix(formatter) {
+        if log::set_boxed_logger(Box::new(BasicLogger::new(logger))).is_ok() {
+            log::set_max_level(LevelFilter::Info);
+        }
     }
 }
 
@@ -89,16 +100,17 @@ pub fn companion_addr() -> String {
     if let Ok(addr) = env::var(ENV_VAR) {
         addr
     } else {
-        // let mut dir = std::env::temp_dir();
-        // dir.push(&format!("{}.sock", PROGRAM_NAME));
-        // dir
         "[::]:2000".into()
     }
 }
 
+pub fn companion_address() -> Address {
+    Address::parse(&companion_addr())
+}
+
 pub fn pid_path() -> PathBuf {
     let mut dir = std::env::temp_dir();
-    dir.push(&format!("{}.pid", PROGRAM_NAME));
+    dir.push(format!("{}.pid", PROGRAM_NAME));
     dir
 }
 
@@ -106,105 +118,137 @@ fn check_started<P>(path: P) -> bool
 where
     P: AsRef<Path>,
 {
-    if let Ok(pids) = fs::read_to_string(&path) {
-        // println!("pids: {pids}");
-        let sys = sysinfo::System::new_all();
-        let processes = sys.processes();
-        let pids: Vec<u32> = pids.lines().filter_map(|s| s.parse::<u32>().ok()).collect();
-        let mut started = false;
-        let mut new_pids = vec![];
-        for pid in pids.iter() {
-            if processes.contains_key(&Pid::from_u32(*pid)) {
-                started = true;
-                new_pids.push(*pid);
-            }
-        }
+    if server::is_locked(path.as_ref()) {
+        return true;
+    }
 
-        if started {
-            let contents = new_pids
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
+    // companions predating the lock leave it unlocked, the file is left as
+    // is since only the lock holder may write it
+    Record::read(path.as_ref()).iter().any(Record::is_running)
+}
 
-            fs::write(&path, contents).unwrap();
-            return true;
+/// Whether the companion at `addr` speaks another protocol than
+/// [`PROTOCOL_VERSION`].
+///
+/// Only asked once the pid file names a running companion. Companions
+/// predating the handshake never answer it and count as outdated, a
+/// companion that can not be reached is left alone, it may serve another
+/// address than the one of this build.
+fn is_outdated(addr: &Address) -> bool {
+    let hello = Client::connect_to(addr)
+        .and_then(|client| client.with_timeout(HELLO_TIMEOUT))
+        .and_then(|client| client.with_retries(0).hello());
+    match hello {
+        Ok(version) => version != PROTOCOL_VERSION,
+        Err(Error::Companion(message)) => message.starts_with(server::UNSUPPORTED),
+        // listening, but not speaking the protocol
+        Err(Error::Timeout | Error::Encoding(_) | Error::Format(_)) => true,
+        Err(err) => {
+            #[cfg(feature = "log")]
+            log::warn!("companion handshake failed: {}", err);
+            false
         }
     }
-    false
 }
 
-pub fn launch<P>(path: P)
+/// Stop the companion listed in the pid file at `path` when it is outdated.
+fn replace_outdated(path: &Path, addr: &Address) -> io::Result<()> {
+    if check_started(path) && is_outdated(addr) {
+        stop(path)?;
+    }
+    Ok(())
+}
+
+/// Terminate the companions listed in the pid file and wait for them to exit.
+fn stop<P>(path: P) -> io::Result<()>
 where
     P: AsRef<Path>,
 {
-    #[cfg(feature = "log")]
-    setup_logger();
-
-    let pid = std::process::id();
+    use nix::sys::signal;
 
-    fs::write(path, pid.to_string()).unwrap();
+    // a recycled pid belongs to somebody else
+    let records: Vec<Record> = Record::read(path.as_ref())
+        .into_iter()
+        .filter(Record::is_running)
+        .collect();
 
-    let socket_path = companion_addr();
-
-    let mut storage: HashMap<String, String> = HashMap::new();
+    for pid in records.iter().filter_map(Record::pid) {
+        let _ = signal::kill(pid, signal::SIGTERM);
+    }
 
-    let sock = UdpSocket::bind(&socket_path).unwrap();
+    let started = Instant::now();
+    while let Some(record) = records.iter().find(|record| record.is_alive()) {
+        if started.elapsed() >= STOP_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("companion {} did not stop", record.pid),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
 
-    'outer: loop {
-        let mut buf = [0; 65507];
-        let sock = sock.try_clone().expect("Failed to clone socket");
+    // left behind by companions predating the lock
+    if !server::is_locked(path.as_ref()) {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
 
-        let (len, src) = sock.recv_from(&mut buf).unwrap();
-        let buf = &mut buf[..len];
+/// Serve the built-in tasks at `companion_addr()` with the snapshot,
+/// capacity and idle timeout taken from the environment, writing the pid to
+/// `path`.
+///
+/// Fails when another companion holds the lock on `path` or the address
+/// can not be bound.
+///
+/// See [`Server::builder`] to configure the companion otherwise.
+pub fn launch<P>(path: P) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut builder = builder(path);
+    if let Some(timeout) = idle_timeout() {
+        builder = builder.idle_timeout(timeout);
+    }
+    builder.build().run()
+}
 
-        let task: Task = bincode::deserialize(buf).unwrap();
-        println!("{task:?}");
-        match task {
-            Task::Get(key) => {
-                #[cfg(feature = "log")]
-                log::info!("get {}", key);
-                match storage.get(key) {
-                    Some(data) => {
-                        let buf = bincode::serialize(&Response::String(data.clone())).unwrap();
-                        sock.send_to(&buf, src).unwrap();
-                    }
-                    None => {
-                        let buf = bincode::serialize(&Response::NotFound).unwrap();
-                        sock.send_to(&buf, src).unwrap();
-                    }
-                }
-            }
-            Task::Set(key, data) => {
-                #[cfg(feature = "log")]
-                log::info!("set {}", key);
-                storage.insert(key.into(), data.into());
-                let buf = bincode::serialize(&Response::Ok).unwrap();
-                sock.send_to(&buf, src).unwrap();
-            }
-            Task::List => {
-                let keys: Vec<String> = storage.keys().map(Clone::clone).collect();
-                let buf = bincode::serialize(&Response::List(keys)).unwrap();
-                sock.send_to(&buf, src).unwrap();
-            }
-            Task::Sum(_values) => {
-                #[cfg(feature = "log")]
-                log::info!("shutdown");
-                // tx.send(Response::NotFound).unwrap();
-            }
-            Task::Shutdown => {
-                #[cfg(feature = "log")]
-                log::info!("shutdown");
-                break 'outer;
-            }
-        }
+/// Companion configured from the environment, without idle timeout.
+fn builder<P>(path: P) -> Builder
+where
+    P: AsRef<Path>,
+{
+    let mut builder = Server::builder()
+        .address(companion_address())
+        .pid_file(path.as_ref())
+        .capacity(capacity())
+        .logging(true)
+        .signals(true);
+    if let Some(snapshot) = snapshot_path() {
+        builder = builder.snapshot(snapshot);
     }
+    if let Some(mut lock_file) = target_dir() {
+        lock_file.push(LOCK_FILE);
+        builder = builder.lock_file(lock_file);
+    }
+    builder
 }
 
-pub fn lockfile() -> String {
+/// Time without requests after which the companion exits, taken from
+/// `RUST_COMPANION_IDLE_TIMEOUT` in seconds. Zero keeps it running, unset or
+/// invalid values yield `None`.
+pub fn idle_timeout() -> Option<Duration> {
+    env::var(IDLE_TIMEOUT_ENV_VAR)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Cargo target directory of the crate being built, derived from `OUT_DIR`.
+pub fn target_dir() -> Option<PathBuf> {
     let mut path = PathBuf::new();
     // from outdir
-    let source = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let source = PathBuf::from(std::env::var_os("OUT_DIR")?);
     let mut prev = String::new();
     for part in source.iter() {
         if prev == "target" && (part == "debug" || part == "release") {
@@ -213,40 +257,227 @@ pub fn lockfile() -> String {
         prev = part.to_string_lossy().into();
         path.push(part);
     }
-    path.push("companion.lock");
+    Some(path)
+}
+
+pub fn lockfile() -> String {
+    let mut path = target_dir().expect("OUT_DIR is not set");
+    path.push(LOCK_FILE);
+    path.as_os_str().to_string_lossy().into()
+}
+
+/// Storage limits of the companion, taken from `RUST_COMPANION_MAX_ENTRIES`
+/// and `RUST_COMPANION_MAX_BYTES`. Unset or invalid values are unlimited.
+pub fn capacity() -> Capacity {
+    let limit = |name| env::var(name).ok().and_then(|value| value.parse().ok());
+    Capacity {
+        max_entries: limit(MAX_ENTRIES_ENV_VAR),
+        max_bytes: limit(MAX_BYTES_ENV_VAR),
+    }
+}
+
+/// Snapshot file next to the [`lockfile`], a suggested value for
+/// `RUST_COMPANION_SNAPSHOT`.
+pub fn snapshot_file() -> String {
+    let mut path = target_dir().expect("OUT_DIR is not set");
+    path.push("companion.snapshot");
     path.as_os_str().to_string_lossy().into()
 }
 
+/// Snapshot the companion persists its values to, taken from
+/// `RUST_COMPANION_SNAPSHOT`. Values are kept in memory only when unset.
+pub fn snapshot_path() -> Option<PathBuf> {
+    env::var_os(SNAPSHOT_ENV_VAR)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Namespace used by [`Client::connect`].
+///
+/// Taken from `RUST_COMPANION_NAMESPACE` when set, otherwise the target
+/// directory of the crate being built so unrelated workspaces do not share
+/// data. Falls back to [`DEFAULT_NAMESPACE`] outside of cargo builds.
+pub fn default_namespace() -> String {
+    if let Ok(namespace) = env::var(NAMESPACE_ENV_VAR) {
+        namespace
+    } else if let Some(dir) = target_dir() {
+        dir.to_string_lossy().into()
+    } else {
+        DEFAULT_NAMESPACE.into()
+    }
+}
+
 pub fn bootstrap() -> std::result::Result<String, Box<dyn std::error::Error>> {
     let pid_path = pid_path();
 
     let lockfile = lockfile();
 
+    // closed on return unless serving, so the spawning process stops waiting
+    let ready = match env::args().nth(1) {
+        Some(arg) if arg == "-d" => ready_fd(),
+        _ => None,
+    };
+
+    // a companion started by another build of the crate may speak another
+    // protocol, replace it
+    replace_outdated(&pid_path, &companion_address())?;
+
     if !check_started(&pid_path) {
         match env::args().nth(1) {
             Some(arg) => {
                 if arg == "-d" {
-                    launch(&pid_path);
+                    // nobody shuts the spawned companion down, let it exit
+                    // once builds are over
+                    let mut builder = builder(&pid_path)
+                        .idle_timeout(idle_timeout().unwrap_or(DEFAULT_IDLE_TIMEOUT));
+                    if let Some(fd) = ready {
+                        builder = builder.notify(fd);
+                    }
+                    builder.build().run()?;
                 }
             }
             None => {
-                match env::current_exe() {
-                    Ok(exe) => {
-                        let _child = std::process::Command::new(&exe)
-                            .arg("-d")
-                            .stderr(Stdio::null())
-                            .stdout(Stdio::null())
-                            .spawn()?;
-                        std::thread::sleep(Duration::from_micros(50));
-                        // write lock file
-                        let exe = exe.as_os_str().to_string_lossy().to_string();
-                        let _ = std::fs::write(&lockfile, &exe);
+                let exe = env::current_exe()?;
+                if let Err(err) = spawn(&exe) {
+                    // a concurrent build may have won the race to start it
+                    if !server::is_locked(&pid_path) {
+                        return Err(err.into());
                     }
-                    Err(e) => println!("failed to get current exe path: {e}"),
-                };
+                }
+                // write lock file
+                let exe = exe.as_os_str().to_string_lossy().to_string();
+                let _ = std::fs::write(&lockfile, &exe);
             }
         }
     }
 
     Ok(lockfile)
 }
+
+/// Spawn the companion from `exe` and wait until it serves.
+///
+/// The companion reports through a pipe whose descriptor is passed in
+/// `RUST_COMPANION_READY_FD`, see [`Builder::notify`].
+fn spawn(exe: &Path) -> io::Result<()> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+        unistd::pipe2,
+    };
+    use std::os::unix::process::CommandExt;
+
+    let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+    // SAFETY: both descriptors were just created and are owned by nobody else
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(read), OwnedFd::from_raw_fd(write)) };
+
+    let fd = write.as_raw_fd();
+    let mut command = std::process::Command::new(exe);
+    command
+        .arg("-d")
+        .env(READY_FD_ENV_VAR, fd.to_string())
+        .stderr(Stdio::null())
+        .stdout(Stdio::null());
+    // SAFETY: fcntl is async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // the read end sees EOF once the companion closes its copy
+    drop(write);
+
+    let message = wait_ready(read)?;
+    if message == server::READY {
+        return Ok(());
+    }
+    if !message.is_empty() {
+        let message = String::from_utf8_lossy(&message);
+        return Err(io::Error::other(format!(
+            "companion failed to start: {message}"
+        )));
+    }
+    // the companion closed the pipe without serving, either it found another
+    // companion running or it died
+    match child.try_wait()? {
+        Some(status) if !status.success() => {
+            Err(io::Error::other(format!("companion exited with {status}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read the readiness pipe to the end, for at most `READY_TIMEOUT`.
+fn wait_ready(fd: OwnedFd) -> io::Result<Vec<u8>> {
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::io::Read;
+
+    let mut pipe = fs::File::from(fd);
+    let deadline = Instant::now() + READY_TIMEOUT;
+    let mut message = vec![];
+    let mut buf = [0; 512];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(pipe.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, remaining.as_millis() as libc::c_int) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "companion did not start in time",
+                ))
+            }
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+        match pipe.read(&mut buf) {
+            Ok(0) => return Ok(message),
+            Ok(len) => message.extend_from_slice(&buf[..len]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Readiness pipe passed down by [`bootstrap`] in the parent process.
+fn ready_fd() -> Option<OwnedFd> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+    let fd: RawFd = env::var(READY_FD_ENV_VAR).ok()?.parse().ok()?;
+    env::remove_var(READY_FD_ENV_VAR);
+    // keep it from processes spawned later, fails unless the descriptor is open
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).ok()?;
+    // SAFETY: the descriptor was left open for this process alone
+    Some(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixDatagram, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn silent_companions_are_replaced() {
+        let dir = env::temp_dir().join(format!("companion-outdated-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("companion.sock");
+        let pid_file = dir.join("companion.pid");
+
+        // predates the handshake, takes the hello for another task and never
+        // answers
+        let _listener = UnixDatagram::bind(&socket).unwrap();
+        let mut companion = Command::new("sleep").arg("60").spawn().unwrap();
+        fs::write(&pid_file, format!("{}\n", Record::of(companion.id()))).unwrap();
+        assert!(check_started(&pid_file));
+        // reaped as soon as it exits, zombies count as alive
+        let exited = std::thread::spawn(move || companion.wait());
+
+        let addr = Address::parse(&format!("unix:{}", socket.display()));
+        replace_outdated(&pid_file, &addr).unwrap();
+        let status = exited.join().unwrap().unwrap();
+        assert!(!status.success());
+        assert!(!pid_file.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
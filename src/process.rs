@@ -0,0 +1,279 @@
+//! Identity of companion processes recorded in the pid file.
+//!
+//! A pid alone may name an unrelated process once the companion died and the
+//! pid was recycled, after a reboot for instance. Each line of the pid file
+//! holds the pid along with the start time and the executable of the
+//! process, a process is taken for the companion only when all of them
+//! match. The start time is kept in clock ticks since boot along with the
+//! boot id, both compared exactly, so adjusting the clock does not change it.
+//!
+use std::{
+    convert::TryFrom,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use nix::{errno::Errno, sys::signal, unistd};
+
+use crate::LOCK_FILE;
+
+/// Line of the pid file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub pid: u32,
+    // unknown in pid files of older companions
+    pub start: Option<Start>,
+    pub exe: Option<PathBuf>,
+}
+
+/// Start time of a process, `<ticks>@<boot>` in the pid file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Start {
+    // clock ticks since boot, seconds since the epoch with sysinfo
+    pub ticks: u64,
+    // boot id, boot time with sysinfo
+    pub boot: String,
+}
+
+impl Start {
+    fn parse(field: &str) -> Option<Self> {
+        let (ticks, boot) = field.split_once('@')?;
+        Some(Start {
+            ticks: ticks.parse().ok()?,
+            boot: boot.into(),
+        })
+    }
+}
+
+impl fmt::Display for Start {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.ticks, self.boot)
+    }
+}
+
+impl Record {
+    /// Record of the current process.
+    pub fn current() -> Self {
+        let pid = std::process::id();
+        Record {
+            pid,
+            start: start_time(pid),
+            exe: env::current_exe().ok(),
+        }
+    }
+
+    /// Record of another running process.
+    #[cfg(test)]
+    pub fn of(pid: u32) -> Self {
+        Record {
+            pid,
+            start: start_time(pid),
+            exe: executable(pid),
+        }
+    }
+
+    /// Parse `<pid> <start> <exe>`, or a bare pid.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.trim().splitn(3, ' ');
+        let pid = fields.next()?.parse().ok()?;
+        let start = match fields.next() {
+            Some(start) => Some(Start::parse(start)?),
+            None => None,
+        };
+        let exe = fields.next().map(PathBuf::from);
+        Some(Record { pid, start, exe })
+    }
+
+    /// Records of the pid file, none when it can not be read.
+    pub fn read(path: &Path) -> Vec<Self> {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(Record::parse)
+            .collect()
+    }
+
+    pub fn pid(&self) -> Option<unistd::Pid> {
+        libc::pid_t::try_from(self.pid)
+            .ok()
+            .map(unistd::Pid::from_raw)
+    }
+
+    /// Whether the process is alive and still the one recorded.
+    pub fn is_running(&self) -> bool {
+        if !self.is_alive() {
+            return false;
+        }
+
+        // what can not be looked up is given the benefit of the doubt
+        if let (Some(recorded), Some(start)) = (&self.start, start_time(self.pid)) {
+            if *recorded != start {
+                return false;
+            }
+        }
+        let Some(exe) = executable(self.pid) else {
+            return true;
+        };
+        match &self.exe {
+            Some(recorded) => *recorded == exe,
+            // older companions, run by this executable or the one recorded
+            // in the lock file
+            None => {
+                let locked = crate::target_dir()
+                    .and_then(|mut path| {
+                        path.push(LOCK_FILE);
+                        fs::read_to_string(path).ok()
+                    })
+                    .map(PathBuf::from);
+                env::current_exe().is_ok_and(|current| current == exe) || locked == Some(exe)
+            }
+        }
+    }
+
+    /// Whether any process has the pid.
+    pub fn is_alive(&self) -> bool {
+        match self.pid().map(|pid| signal::kill(pid, None)) {
+            // alive, owned by another user
+            Some(Ok(()) | Err(Errno::EPERM)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pid)?;
+        if let Some(start) = &self.start {
+            write!(f, " {start}")?;
+            if let Some(exe) = &self.exe {
+                write!(f, " {}", exe.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Executable of a running process.
+#[cfg(not(feature = "sysinfo"))]
+fn executable(pid: u32) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/{pid}/exe")).ok()
+}
+
+/// Start time of a running process.
+#[cfg(not(feature = "sysinfo"))]
+fn start_time(pid: u32) -> Option<Start> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let boot = fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+    Some(Start {
+        ticks: start_ticks(&stat)?,
+        boot: boot.trim().into(),
+    })
+}
+
+/// Start time in clock ticks since boot from the contents of
+/// `/proc/<pid>/stat`.
+#[cfg(not(feature = "sysinfo"))]
+fn start_ticks(stat: &str) -> Option<u64> {
+    // the command name in parentheses may hold spaces, the start time is
+    // the 22nd field counting it
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Executable of a running process.
+#[cfg(feature = "sysinfo")]
+fn executable(pid: u32) -> Option<PathBuf> {
+    use sysinfo::ProcessExt;
+
+    process(pid, |process| process.exe().to_path_buf()).filter(|exe| !exe.as_os_str().is_empty())
+}
+
+/// Start time of a running process, in seconds as sysinfo has no clock
+/// ticks.
+#[cfg(feature = "sysinfo")]
+fn start_time(pid: u32) -> Option<Start> {
+    use sysinfo::{ProcessExt, SystemExt};
+
+    process(pid, |process| Start {
+        ticks: process.start_time(),
+        boot: sysinfo::System::new().boot_time().to_string(),
+    })
+}
+
+#[cfg(feature = "sysinfo")]
+fn process<T>(pid: u32, f: impl FnOnce(&sysinfo::Process) -> T) -> Option<T> {
+    use sysinfo::{Pid, PidExt, SystemExt};
+
+    // only the one process is loaded
+    let mut sys = sysinfo::System::new();
+    let pid = Pid::from_u32(pid);
+    if !sys.refresh_process(pid) {
+        return None;
+    }
+    sys.process(pid).map(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let record = Record {
+            pid: 42,
+            start: Some(Start {
+                ticks: 98765,
+                boot: "6d1c1a4e-41d5-4b6a-9a4c-6a8e0c2b9f11".into(),
+            }),
+            exe: Some("/path with spaces/build-script-build".into()),
+        };
+        assert_eq!(Record::parse(&record.to_string()), Some(record));
+    }
+
+    #[test]
+    fn bare_pids_parse() {
+        let record = Record::parse("42\n").unwrap();
+        assert_eq!((record.pid, record.start, record.exe), (42, None, None));
+        assert_eq!(Record::parse(""), None);
+        assert_eq!(Record::parse("pid"), None);
+        assert_eq!(Record::parse("42 soon /bin/true"), None);
+        // seconds since the epoch, written by earlier builds
+        assert_eq!(Record::parse("42 1700000000 /bin/true"), None);
+    }
+
+    #[test]
+    fn current_process_is_running() {
+        let record = Record::current();
+        assert!(record.is_running());
+        let later = Record {
+            start: record.start.clone().map(|start| Start {
+                ticks: start.ticks + 1,
+                ..start
+            }),
+            ..record.clone()
+        };
+        assert_eq!(later.is_running(), record.start.is_none());
+        let rebooted = Record {
+            start: record.start.clone().map(|start| Start {
+                boot: "another boot".into(),
+                ..start
+            }),
+            ..record.clone()
+        };
+        assert_eq!(rebooted.is_running(), record.start.is_none());
+        let other = Record {
+            exe: Some("/nonexistent".into()),
+            ..record
+        };
+        assert!(!other.is_running());
+    }
+
+    #[cfg(not(feature = "sysinfo"))]
+    #[test]
+    fn start_ticks_skip_the_command_name() {
+        let stat = "1234 (a) b (c) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 \
+                    98765 1000 200 18446744073709551615";
+        assert_eq!(start_ticks(stat), Some(98765));
+        assert_eq!(start_ticks("1234 (truncated"), None);
+    }
+}
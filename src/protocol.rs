@@ -0,0 +1,217 @@
+//! Messages exchanged between clients and the companion.
+//!
+//! Decoding never panics, a malformed datagram is reported as
+//! [`Error::Encoding`] so the companion can answer it with
+//! [`Response::Error`] and keep serving.
+//!
+//! Every message travels in an [`Envelope`] tagging it with the protocol
+//! version and the id of the request, so a reply can be matched with the
+//! request it answers.
+//!
+use std::{borrow::Cow, convert::TryFrom, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Version of the wire format, bumped whenever `Task` or `Response` change.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// A request or response along with the request id it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope<T> {
+    pub version: u32,
+    // chosen by the client, echoed back in the reply
+    pub id: u64,
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: u64, body: T) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            body,
+        }
+    }
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl<'a, T: Deserialize<'a>> TryFrom<&'a [u8]> for Envelope<T> {
+    type Error = Error;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Version and request id of an envelope whose body could not be decoded.
+pub(crate) fn header(bytes: &[u8]) -> Option<(u32, u64)> {
+    bincode::deserialize(bytes).ok()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    String(String),
+    List(Vec<String>),
+    Ok,
+    NotFound,
+    // File descriptor is attached to the datagram
+    Fd,
+    // Request could not be served
+    Error(String),
+    Integer(i64),
+    Float(f64),
+    // Arithmetic result does not fit in the response type
+    Overflow,
+    Bool(bool),
+    // Number of affected entries
+    Count(u64),
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    Stats(Stats),
+    // Protocol version spoken by the companion
+    Hello { version: u32 },
+}
+
+/// Storage statistics of the companion.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub namespaces: u64,
+    pub entries: u64,
+    // total size of the keys and values
+    pub bytes: u64,
+    pub max_entries: Option<u64>,
+    pub max_bytes: Option<u64>,
+    // values removed to stay within capacity
+    pub evictions: u64,
+    // values removed after their time to live
+    pub expirations: u64,
+}
+
+impl Response {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+}
+
+impl TryFrom<&[u8]> for Response {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Task<'a> {
+    // Get data by name
+    Get(Cow<'a, str>),
+    // Store data by name
+    Set(Cow<'a, str>, Cow<'a, str>),
+    // List stored names
+    List,
+    // Sum of the values, checked for overflow
+    Sum(Vec<i64>),
+    Shutdown,
+    // Store the file descriptor attached to the datagram by name
+    SetFd(Cow<'a, str>),
+    // Get a duplicate of a stored file descriptor by name
+    GetFd(Cow<'a, str>),
+    // Smallest of the values, `NotFound` when empty
+    Min(Vec<i64>),
+    // Largest of the values, `NotFound` when empty
+    Max(Vec<i64>),
+    // Arithmetic mean of the values, `NotFound` when empty
+    Mean(Vec<i64>),
+    // Add delta to the integer stored by name, missing values count as 0
+    Incr(Cow<'a, str>, i64),
+    // Replace the value only if it currently equals the expected one,
+    // `None` expects the name to be absent
+    CompareAndSwap(Cow<'a, str>, Option<Cow<'a, str>>, Cow<'a, str>),
+    // Store data by name unless the name is already taken
+    SetIfAbsent(Cow<'a, str>, Cow<'a, str>),
+    // Remove data and file descriptor stored by name
+    Delete(Cow<'a, str>),
+    // Remove everything stored under names starting with the prefix
+    DeletePrefix(Cow<'a, str>),
+    // Remove everything stored in the namespace, other namespaces are kept
+    Clear,
+    // Apply the task to the named namespace instead of the default one
+    Namespaced(Cow<'a, str>, Box<Task<'a>>),
+    // List namespaces holding any data
+    Namespaces,
+    // Store binary data by name
+    SetBytes(Cow<'a, str>, #[serde(with = "serde_bytes")] Vec<u8>),
+    // Store data by name for the given time
+    SetWithTtl(Cow<'a, str>, Cow<'a, str>, Duration),
+    // Store binary data by name for the given time
+    SetBytesWithTtl(
+        Cow<'a, str>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        Duration,
+    ),
+    // Keep the value stored by name for the given time from now
+    Touch(Cow<'a, str>, Duration),
+    // Storage statistics
+    Stats,
+    // Handshake announcing the protocol version of the client
+    Hello {
+        version: u32,
+    },
+    // Invoke the handler registered with the server under the name
+    Call(Cow<'a, str>, #[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl<'a> Task<'a> {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    /// Name of the stored value the task works on.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Task::Get(key)
+            | Task::Set(key, _)
+            | Task::SetFd(key)
+            | Task::GetFd(key)
+            | Task::Incr(key, _)
+            | Task::CompareAndSwap(key, ..)
+            | Task::SetIfAbsent(key, _)
+            | Task::Delete(key)
+            | Task::SetBytes(key, _)
+            | Task::SetWithTtl(key, ..)
+            | Task::SetBytesWithTtl(key, ..)
+            | Task::Touch(key, _) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the task may be sent again when its reply is lost. The
+    /// atomic tasks may not, a resent one is applied twice or compared
+    /// against its own write.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Task::Incr(..) | Task::CompareAndSwap(..) | Task::SetIfAbsent(..) => false,
+            Task::Namespaced(_, task) => task.is_retryable(),
+            _ => true,
+        }
+    }
+
+    /// Wrap the task to apply it to the named namespace.
+    pub fn namespaced<S: Into<Cow<'a, str>>>(self, name: S) -> Self {
+        Task::Namespaced(name.into(), Box::new(self))
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Task<'a> {
+    type Error = Error;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Error> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
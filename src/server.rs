@@ -0,0 +1,903 @@
+//! Request loops of the companion.
+//!
+//! Applications extend the companion by registering named handlers with
+//! [`Server::builder`], clients invoke them with [`Task::Call`].
+//!
+//! Requests are served concurrently while every task is applied to the
+//! shared [`State`] under one lock, so each task is atomic with respect to
+//! the others. Datagram transports are served by a pool of workers receiving
+//! from the same socket. Stream transports get a thread per connection.
+//!
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    poll::{poll, PollFd, PollFlags},
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+
+use crate::{
+    process::Record, protocol, Address, Capacity, Codec, Envelope, Listener, Peer, Response,
+    Socket, State, Stream, Task, MAX_DATAGRAM, PROTOCOL_VERSION,
+};
+
+/// How often expired values are reclaimed and changed values are written to
+/// the snapshot file.
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often blocked threads wake up to notice shutdown.
+const WAKE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Upper bound of datagram workers.
+const MAX_WORKERS: usize = 8;
+
+/// How long a pid file locked by somebody else is retried.
+const LOCK_RETRY: Duration = Duration::from_millis(100);
+
+/// Written to the readiness pipe once the address is bound.
+pub(crate) const READY: &[u8] = b"ready";
+
+/// Start of the error answering requests in another protocol version.
+pub(crate) const UNSUPPORTED: &str = "unsupported protocol version";
+
+/// Application defined task, called with the storage and the payload of
+/// [`Task::Call`].
+pub type Handler = Box<dyn Fn(&mut State, &[u8]) -> Response + Send + Sync>;
+
+type Handlers = HashMap<String, Handler>;
+
+/// Companion serving the built-in tasks and any registered handlers.
+///
+/// ```no_run
+/// let server = companion::Server::builder()
+///     .address(companion::Address::parse("unix:/tmp/companion.sock"))
+///     .build();
+/// let handle = server.spawn()?;
+/// // talk to it with `companion::Client::connect_to`
+/// handle.stop()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Server {
+    addr: Address,
+    pid_file: Option<PathBuf>,
+    lock_file: Option<PathBuf>,
+    snapshot: Option<PathBuf>,
+    logging: bool,
+    signals: bool,
+    idle_timeout: Option<Duration>,
+    handlers: Handlers,
+    // readiness pipe, closed once written to
+    notify: Mutex<Option<OwnedFd>>,
+    // pid file, locked while serving
+    pid_lock: Mutex<Option<fs::File>>,
+    state: Mutex<State>,
+    stopping: Arc<AtomicBool>,
+    started: Instant,
+    // milliseconds since `started` when the last request arrived
+    active: AtomicU64,
+}
+
+/// Configuration of a [`Server`].
+///
+/// Only the address defaults to the one in `RUST_COMPANION`, see
+/// [`crate::companion_address`]. Nothing else is taken from the environment,
+/// see [`crate::launch`] for the configuration used by the self-spawned
+/// companion.
+#[derive(Default)]
+pub struct Builder {
+    addr: Option<Address>,
+    pid_file: Option<PathBuf>,
+    lock_file: Option<PathBuf>,
+    state: Option<State>,
+    snapshot: Option<PathBuf>,
+    capacity: Option<Capacity>,
+    logging: bool,
+    signals: bool,
+    idle_timeout: Option<Duration>,
+    handlers: Handlers,
+    notify: Option<OwnedFd>,
+}
+
+impl Builder {
+    /// Address to serve at, `companion_addr()` by default.
+    pub fn address(mut self, addr: Address) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// File to write the pid of the companion to. It is locked while
+    /// serving, so only one companion serves per pid file.
+    pub fn pid_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pid_file = Some(path.into());
+        self
+    }
+
+    /// File removed along with the pid file once serving ends.
+    pub fn lock_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.lock_file = Some(path.into());
+        self
+    }
+
+    /// Storage to serve, instead of the one loaded from the snapshot file.
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Snapshot file the storage is loaded from and written to.
+    pub fn snapshot<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.snapshot = Some(path.into());
+        self
+    }
+
+    /// Bounds of the storage, unlimited by default.
+    pub fn capacity(mut self, capacity: Capacity) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Log to syslog, needs the `log` feature.
+    pub fn logging(mut self, enabled: bool) -> Self {
+        self.logging = enabled;
+        self
+    }
+
+    /// Stop serving gracefully on SIGTERM and SIGINT, like on
+    /// [`Task::Shutdown`]. The handlers are process wide.
+    pub fn signals(mut self, enabled: bool) -> Self {
+        self.signals = enabled;
+        self
+    }
+
+    /// Stop serving after no request arrived for `timeout`, a zero timeout
+    /// keeps serving.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Pipe to report readiness to. Once bound the server writes `ready`,
+    /// otherwise the bind error, then closes it.
+    pub fn notify(mut self, fd: OwnedFd) -> Self {
+        self.notify = Some(fd);
+        self
+    }
+
+    /// Register a handler invoked by `Task::Call` with the given name,
+    /// replacing any handler registered under the same name. A handler that
+    /// panics is answered with `Response::Error`.
+    pub fn handler<S, F>(mut self, name: S, handler: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&mut State, &[u8]) -> Response + Send + Sync + 'static,
+    {
+        self.handlers.insert(name.into(), Box::new(handler));
+        self
+    }
+
+    /// Create the server, loading the snapshot unless a storage is given.
+    pub fn build(self) -> Server {
+        let mut state = match (self.state, &self.snapshot) {
+            (Some(state), _) => state,
+            (None, Some(path)) => State::load(path).unwrap_or_else(|err| {
+                #[cfg(feature = "log")]
+                log::warn!("can not load snapshot {}: {}", path.display(), err);
+                State::new()
+            }),
+            (None, None) => State::new(),
+        };
+        if let Some(capacity) = self.capacity {
+            state.set_capacity(capacity);
+        }
+
+        Server {
+            addr: self.addr.unwrap_or_else(crate::companion_address),
+            pid_file: self.pid_file,
+            lock_file: self.lock_file,
+            snapshot: self.snapshot,
+            logging: self.logging,
+            signals: self.signals,
+            idle_timeout: self.idle_timeout,
+            handlers: self.handlers,
+            notify: Mutex::new(self.notify),
+            pid_lock: Mutex::new(None),
+            state: Mutex::new(state),
+            stopping: Arc::new(AtomicBool::new(false)),
+            started: Instant::now(),
+            active: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Server running in a thread of its own, see [`Server::spawn`].
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: Address,
+    stopping: Arc<AtomicBool>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    pub fn address(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Stop serving and wait for the server to finish.
+    pub fn stop(self) -> io::Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.join()
+    }
+
+    /// Wait until a client asks for shutdown or the server stops otherwise.
+    pub fn join(self) -> io::Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("companion panicked")))
+    }
+}
+
+/// Set by SIGTERM and SIGINT, for servers configured to handle them.
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+fn handle_signals() -> io::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: the handler only stores to an atomic
+        unsafe { sigaction(signal, &action) }?;
+    }
+    Ok(())
+}
+
+/// Bound socket of either kind of transport.
+enum Bound {
+    Datagram(Socket),
+    Stream(Listener),
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Serve in the current thread until a client asks for shutdown or
+    /// [`Server::stop`] is called.
+    pub fn run(&self) -> io::Result<()> {
+        let bound = self.bind()?;
+        self.serve(bound)
+    }
+
+    /// Serve in a new thread. Returns once the address is bound, so clients
+    /// can connect right away.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let bound = self.bind()?;
+        let addr = self.addr.clone();
+        let stopping = self.stopping.clone();
+        let thread = thread::Builder::new()
+            .name(crate::PROGRAM_NAME.into())
+            .spawn(move || self.serve(bound))?;
+        Ok(ServerHandle {
+            addr,
+            stopping,
+            thread,
+        })
+    }
+
+    /// Ask a running server to stop, it finishes the requests in flight.
+    /// Stopping writes the snapshot and removes the socket, pid and lock
+    /// files.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Bind and report the outcome to the readiness pipe.
+    fn bind(&self) -> io::Result<Bound> {
+        let bound = self.try_bind();
+        if bound.is_err() {
+            self.remove_pid_file();
+        }
+        if let Some(fd) = self.notify.lock().unwrap().take() {
+            let message = match &bound {
+                Ok(_) => READY.to_vec(),
+                Err(err) => err.to_string().into_bytes(),
+            };
+            // the spawning process may be gone already
+            let _ = fs::File::from(fd).write_all(&message);
+        }
+        bound
+    }
+
+    fn try_bind(&self) -> io::Result<Bound> {
+        if self.logging {
+            #[cfg(feature = "log")]
+            crate::setup_logger();
+        }
+
+        if self.signals {
+            handle_signals()?;
+        }
+
+        if let Some(path) = &self.pid_file {
+            *self.pid_lock.lock().unwrap() = Some(lock_pid_file(path)?);
+        }
+
+        if self.addr.is_stream() {
+            Ok(Bound::Stream(Listener::bind(&self.addr)?))
+        } else {
+            Ok(Bound::Datagram(Socket::bind(&self.addr)?))
+        }
+    }
+
+    /// Serve on the bound socket, then write the snapshot and remove the
+    /// socket, pid and lock files.
+    fn serve(&self, bound: Bound) -> io::Result<()> {
+        self.touch();
+
+        let served = thread::scope(|scope| {
+            scope.spawn(|| self.maintain());
+
+            let served = match &bound {
+                Bound::Datagram(sock) => self.serve_datagrams(sock),
+                Bound::Stream(listener) => self.serve_streams(listener),
+            };
+            self.stop();
+            served
+        });
+
+        flush(&mut self.state.lock().unwrap(), self.snapshot.as_deref());
+        // removes the UNIX socket file
+        drop(bound);
+        self.cleanup();
+        served
+    }
+
+    /// Remove the pid file unless another companion took it over, and the
+    /// lock file.
+    fn cleanup(&self) {
+        self.remove_pid_file();
+        if let Some(path) = &self.lock_file {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Remove the pid file and release the lock on it, if held.
+    fn remove_pid_file(&self) {
+        // removed before the lock is released, so the next companion locks
+        // a file of its own
+        if let (Some(path), Some(lock)) = (&self.pid_file, self.pid_lock.lock().unwrap().take()) {
+            let _ = fs::remove_file(path);
+            drop(lock);
+        }
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst) || (self.signals && SIGNALLED.load(Ordering::SeqCst))
+    }
+
+    /// Note that a request arrived, for the idle timeout.
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.active.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let active = Duration::from_millis(self.active.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(active)
+    }
+
+    fn serve_datagrams(&self, sock: &Socket) -> io::Result<()> {
+        // wake up periodically to notice shutdown while idle
+        sock.set_read_timeout(Some(WAKE_INTERVAL))?;
+
+        let workers = thread::available_parallelism().map_or(1, |n| n.get().min(MAX_WORKERS));
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    let mut buf = vec![0; MAX_DATAGRAM];
+
+                    while !self.is_stopping() {
+                        let (len, src, fds) = match sock.recv_from_with_fds(&mut buf) {
+                            Ok(received) => received,
+                            Err(err)
+                                if matches!(
+                                    err.kind(),
+                                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                                ) =>
+                            {
+                                continue
+                            }
+                            Err(err) => {
+                                #[cfg(feature = "log")]
+                                log::warn!("receive failed: {}", err);
+                                continue;
+                            }
+                        };
+
+                        let response = self.process(&buf[..len], fds);
+                        reply(sock, &response, &src);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    fn serve_streams(&self, listener: &Listener) -> io::Result<()> {
+        thread::scope(|scope| {
+            while !self.is_stopping() {
+                if !readable(listener.as_raw_fd())? {
+                    continue;
+                }
+
+                match listener.accept() {
+                    Ok(stream) => {
+                        // a client stalling mid-message must not hold its thread forever
+                        stream.set_read_timeout(Some(FLUSH_INTERVAL))?;
+                        stream.set_write_timeout(Some(FLUSH_INTERVAL))?;
+                        scope.spawn(move || self.serve_connection(&stream));
+                    }
+                    Err(err) => {
+                        #[cfg(feature = "log")]
+                        log::warn!("accept failed: {}", err);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn serve_connection(&self, stream: &Stream) {
+        while !self.is_stopping() {
+            match readable(stream.as_raw_fd()) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => return,
+            }
+
+            // a client that went away or sent garbage framing is dropped
+            let (bytes, fds) = match stream.recv_message() {
+                Ok(received) => received,
+                Err(_) => return,
+            };
+
+            let response = self.process(&bytes, fds);
+            if let Err(err) = stream.send_message(&response.bytes(), &response.fds()) {
+                #[cfg(feature = "log")]
+                log::warn!("reply failed: {}", err);
+                return;
+            }
+        }
+    }
+
+    /// Decode and apply one request.
+    ///
+    /// Shutdown is acknowledged before the server stops.
+    fn process(&self, bytes: &[u8], fds: Vec<OwnedFd>) -> Reply {
+        self.touch();
+
+        let codec = Codec::detect(bytes);
+        let Envelope {
+            version,
+            id,
+            body: task,
+        } = match codec.decode::<Envelope<Task>>(bytes) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                #[cfg(feature = "log")]
+                log::warn!("malformed request: {}", err);
+                // answer with the request id when at least the header is intact
+                let header = match codec {
+                    Codec::Bincode => protocol::header(bytes),
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                };
+                return match header {
+                    Some((version, id)) if version != PROTOCOL_VERSION => {
+                        Reply::new(codec, id, unsupported(version))
+                    }
+                    Some((_, id)) => Reply::new(codec, id, Response::Error(err.to_string())),
+                    None => Reply::new(codec, 0, Response::Error(err.to_string())),
+                };
+            }
+        };
+        if version != PROTOCOL_VERSION {
+            return Reply::new(codec, id, unsupported(version));
+        }
+
+        if let Task::Shutdown = task {
+            #[cfg(feature = "log")]
+            log::info!("shutdown");
+            self.stop();
+            return Reply::new(codec, id, Response::Ok);
+        }
+
+        if let Task::Hello { version } = task {
+            let response = if version == PROTOCOL_VERSION {
+                Response::Hello {
+                    version: PROTOCOL_VERSION,
+                }
+            } else {
+                unsupported(version)
+            };
+            return Reply::new(codec, id, response);
+        }
+
+        if let Task::Call(name, payload) = task {
+            let response = match self.handlers.get(name.as_ref()) {
+                Some(handler) => {
+                    let mut state = self.state.lock().unwrap();
+                    // caught under the lock, so a panicking handler does not
+                    // poison it
+                    panic::catch_unwind(AssertUnwindSafe(|| handler(&mut state, &payload)))
+                        .unwrap_or_else(|panic| {
+                            let message = panic
+                                .downcast_ref::<&str>()
+                                .copied()
+                                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                                .unwrap_or("unknown cause");
+                            #[cfg(feature = "log")]
+                            log::error!("handler {} panicked: {}", name, message);
+                            Response::Error(format!("handler {name} panicked: {message}"))
+                        })
+                }
+                None => Response::Error(format!("no handler named {name}")),
+            };
+            return Reply::new(codec, id, response);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match state.handle(task, fds) {
+            // SAFETY: the descriptor stays open while the state is locked
+            (response, Some(fd)) => {
+                match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+                    Ok(fd) => Reply {
+                        fd: Some(fd),
+                        ..Reply::new(codec, id, response)
+                    },
+                    Err(err) => Reply::new(codec, id, Response::Error(err.to_string())),
+                }
+            }
+            (response, None) => Reply::new(codec, id, response),
+        }
+    }
+
+    /// Periodically reclaim expired values, write the snapshot and stop
+    /// once idle for too long.
+    fn maintain(&self) {
+        let mut flushed = Instant::now();
+        while !self.is_stopping() {
+            thread::sleep(WAKE_INTERVAL);
+            if flushed.elapsed() >= FLUSH_INTERVAL {
+                let mut state = self.state.lock().unwrap();
+                state.sweep();
+                flush(&mut state, self.snapshot.as_deref());
+                flushed = Instant::now();
+            }
+            if self
+                .idle_timeout
+                .is_some_and(|timeout| self.idle() >= timeout)
+            {
+                #[cfg(feature = "log")]
+                log::info!("idle for {:?}, stopping", self.idle());
+                self.stop();
+            }
+        }
+    }
+}
+
+/// Lock the pid file and write the identity of the process to it.
+///
+/// A file left by a companion that died is not locked by anyone, its
+/// contents are replaced.
+fn lock_pid_file(path: &Path) -> io::Result<fs::File> {
+    let started = Instant::now();
+    loop {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            // `is_locked` holds the lock for a moment only
+            Err(Errno::EWOULDBLOCK) if started.elapsed() < LOCK_RETRY => {
+                thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            Err(Errno::EWOULDBLOCK) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("companion already running, see {}", path.display()),
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        }
+        // the previous companion may have removed the file between opening
+        // and locking it, lock the one at the path instead
+        if !same_file(&file, path) {
+            continue;
+        }
+        file.set_len(0)?;
+        file.write_all(Record::current().to_string().as_bytes())?;
+        return Ok(file);
+    }
+}
+
+/// Whether a running companion holds the lock on the pid file.
+pub(crate) fn is_locked(path: &Path) -> bool {
+    match fs::File::open(path) {
+        // a shared lock is released along with the file
+        Ok(file) => {
+            flock(file.as_raw_fd(), FlockArg::LockSharedNonblock) == Err(Errno::EWOULDBLOCK)
+        }
+        Err(_) => false,
+    }
+}
+
+fn same_file(file: &fs::File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => opened.dev() == current.dev() && opened.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+/// Wait up to [`WAKE_INTERVAL`] for `fd` to become readable.
+fn readable(fd: RawFd) -> io::Result<bool> {
+    let mut polled = [PollFd::new(fd, PollFlags::POLLIN)];
+    match poll(&mut polled, WAKE_INTERVAL.as_millis() as i32) {
+        Ok(_) => Ok(polled[0].revents().is_some_and(|events| !events.is_empty())),
+        Err(Errno::EINTR) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reply to one request, encoded in the format of the request.
+struct Reply {
+    codec: Codec,
+    envelope: Envelope<Response>,
+    // duplicate of a stored descriptor, so a concurrent delete can not
+    // close it before the reply is sent
+    fd: Option<OwnedFd>,
+}
+
+impl Reply {
+    fn new(codec: Codec, id: u64, response: Response) -> Self {
+        Self {
+            codec,
+            envelope: Envelope::new(id, response),
+            fd: None,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.codec.encode(&self.envelope).unwrap()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        self.fd.iter().map(AsRawFd::as_raw_fd).collect()
+    }
+}
+
+fn unsupported(version: u32) -> Response {
+    Response::Error(format!(
+        "{UNSUPPORTED} {version}, expected {PROTOCOL_VERSION}"
+    ))
+}
+
+/// Write the snapshot if values changed since it was last written.
+fn flush(state: &mut State, snapshot: Option<&Path>) {
+    if let Some(path) = snapshot {
+        if state.is_dirty() {
+            if let Err(err) = state.save(path) {
+                #[cfg(feature = "log")]
+                log::warn!("can not save snapshot {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Send a reply, a client that went away must not take the companion down.
+fn reply(sock: &Socket, response: &Reply, peer: &Peer) {
+    let mut bytes = response.bytes();
+    if bytes.len() > MAX_DATAGRAM {
+        let error = Response::Error("response does not fit in a datagram".into());
+        bytes = Reply::new(response.codec, response.envelope.id, error).bytes();
+    }
+    if let Err(err) = sock.send_to_with_fds(&bytes, &response.fds(), peer) {
+        #[cfg(feature = "log")]
+        log::warn!("reply to {} failed: {}", peer, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::{Client, Connection, Error};
+
+    /// Address named in the directory of a test.
+    type Addresses = fn(&Path, &str) -> Address;
+
+    fn unix(dir: &Path, name: &str) -> Address {
+        Address::parse(&format!("unix:{}", dir.join(name).display()))
+    }
+
+    fn tcp(_: &Path, _: &str) -> Address {
+        // free once the probe is dropped
+        let probe = TcpListener::bind("127.0.0.1:0").unwrap();
+        Address::parse(&format!("tcp:{}", probe.local_addr().unwrap()))
+    }
+
+    /// Run the test on a UNIX and a TCP address, with a directory of its own
+    /// for the files.
+    fn each(name: &str, test: impl Fn(&Path, Addresses)) {
+        for (scheme, address) in [("unix", unix as Addresses), ("tcp", tcp)] {
+            let dir = std::env::temp_dir()
+                .join(format!("companion-{name}-{scheme}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            test(&dir, address);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    fn builder(dir: &Path, addr: Address) -> Builder {
+        Server::builder()
+            .address(addr)
+            .pid_file(dir.join("companion.pid"))
+            .lock_file(dir.join("companion.lock"))
+            .handler("echo", |_, payload| Response::Bytes(payload.to_vec()))
+            .handler("slow", |_, _| {
+                thread::sleep(Duration::from_millis(200));
+                Response::Ok
+            })
+            .handler("panic", |_, _| panic!("on purpose"))
+    }
+
+    #[test]
+    fn malformed_requests_are_answered_with_errors() {
+        each("malformed", |dir, address| {
+            let handle = builder(dir, address(dir, "companion.sock"))
+                .build()
+                .spawn()
+                .unwrap();
+            let connection = Connection::connect(handle.address()).unwrap();
+            connection
+                .set_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+
+            // intact header, unknown task
+            let mut bytes = Codec::Bincode.encode(&(PROTOCOL_VERSION, 7u64)).unwrap();
+            bytes.extend_from_slice(&[0xff; 4]);
+            connection.send(&bytes, &[]).unwrap();
+            let (reply, _) = connection.recv().unwrap();
+            let reply: Envelope<Response> = Codec::Bincode.decode(&reply).unwrap();
+            assert_eq!(reply.id, 7);
+            assert!(matches!(reply.body, Response::Error(_)));
+
+            handle.stop().unwrap();
+        });
+    }
+
+    #[test]
+    fn stale_replies_are_discarded() {
+        each("stale", |dir, address| {
+            let handle = builder(dir, address(dir, "companion.sock"))
+                .build()
+                .spawn()
+                .unwrap();
+            let client = Client::connect_to(handle.address())
+                .unwrap()
+                .with_timeout(Duration::from_millis(50))
+                .unwrap()
+                .with_retries(0);
+            client.set("a", "1").unwrap();
+
+            assert!(matches!(client.call("slow", b""), Err(Error::Timeout)));
+            // the reply to the slow call arrives first
+            let client = client.with_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(client.get("a").unwrap().as_deref(), Some("1"));
+
+            handle.stop().unwrap();
+        });
+    }
+
+    #[test]
+    fn calls_are_dispatched_to_handlers() {
+        each("handlers", |dir, address| {
+            let handle = builder(dir, address(dir, "companion.sock"))
+                .build()
+                .spawn()
+                .unwrap();
+            let client = Client::connect_to(handle.address()).unwrap();
+
+            let echo = client.call("echo", b"payload").unwrap();
+            assert!(matches!(echo, Response::Bytes(bytes) if bytes == b"payload"));
+            let missing = client.call("missing", b"");
+            assert!(
+                matches!(missing, Err(Error::Companion(message)) if message.contains("missing"))
+            );
+
+            let panicked = client.call("panic", b"");
+            assert!(
+                matches!(panicked, Err(Error::Companion(message)) if message.contains("on purpose"))
+            );
+            // the storage survives the panic
+            client.set("a", "1").unwrap();
+            assert_eq!(client.get("a").unwrap().as_deref(), Some("1"));
+
+            handle.stop().unwrap();
+        });
+    }
+
+    #[test]
+    fn shutdown_removes_the_files() {
+        each("shutdown", |dir, address| {
+            let addr = address(dir, "companion.sock");
+            let lock_file = dir.join("companion.lock");
+            fs::write(&lock_file, "companion").unwrap();
+            let handle = builder(dir, addr.clone()).build().spawn().unwrap();
+            let pid_file = dir.join("companion.pid");
+            assert!(is_locked(&pid_file));
+
+            Client::connect_to(&addr).unwrap().shutdown().unwrap();
+            handle.join().unwrap();
+            assert!(!pid_file.exists());
+            assert!(!lock_file.exists());
+            // only the pid and lock files were in there besides the socket
+            assert_eq!(dir.read_dir().unwrap().count(), 0);
+        });
+    }
+
+    #[test]
+    fn pid_files_are_served_once() {
+        each("pid", |dir, address| {
+            let handle = builder(dir, address(dir, "first.sock"))
+                .build()
+                .spawn()
+                .unwrap();
+
+            let err = builder(dir, address(dir, "second.sock"))
+                .build()
+                .spawn()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            // the pid file still belongs to the first server
+            assert!(is_locked(&dir.join("companion.pid")));
+
+            handle.stop().unwrap();
+            assert!(!dir.join("companion.pid").exists());
+        });
+    }
+}
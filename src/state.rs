@@ -0,0 +1,893 @@
+//! Data the companion keeps between requests.
+//!
+//! Names are stored in namespaces so unrelated workspaces sharing one
+//! companion do not clobber each other. Tasks wrapped in
+//! [`Task::Namespaced`] apply to that namespace, everything else applies to
+//! [`DEFAULT_NAMESPACE`].
+//!
+//! Values can be saved to and loaded from a snapshot file so they survive
+//! restarts of the companion, file descriptors are never persisted.
+//!
+//! Values stored with a time to live are invisible once they expire and are
+//! reclaimed by [`State::sweep`].
+//!
+//! With a limited [`Capacity`] the least recently used values are evicted
+//! once the number of entries or their total size exceeds it.
+//!
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    os::unix::io::{AsRawFd, OwnedFd, RawFd},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Response, Stats, Task};
+
+/// Namespace used by tasks sent without one.
+pub const DEFAULT_NAMESPACE: &str = "";
+
+/// Stored value, strings are kept apart from binary data so they are
+/// returned the way they were stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Value::String(value) => value.as_bytes(),
+            Value::Bytes(value) => value,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<Value> for Response {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(value) => Response::String(value),
+            Value::Bytes(value) => Response::Bytes(value),
+        }
+    }
+}
+
+/// Limits on the values kept by the companion, `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capacity {
+    pub max_entries: Option<usize>,
+    // sum of the key and value lengths
+    pub max_bytes: Option<usize>,
+}
+
+impl Capacity {
+    pub fn is_limited(&self) -> bool {
+        self.max_entries.is_some() || self.max_bytes.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    value: Value,
+    expires: Option<SystemTime>,
+    // position in the LRU order
+    #[serde(skip)]
+    used: u64,
+}
+
+impl Entry {
+    fn new(value: Value, ttl: Option<Duration>) -> Self {
+        Self {
+            value,
+            expires: ttl.and_then(|ttl| expiry(SystemTime::now(), ttl)),
+            used: 0,
+        }
+    }
+
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.value.len()
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// When a value stored for `ttl` from `now` expires, `None` for a time too
+/// far ahead to represent, which never comes.
+fn expiry(now: SystemTime, ttl: Duration) -> Option<SystemTime> {
+    now.checked_add(ttl)
+}
+
+/// Size of the entry a task stores, counted like [`Entry::size`].
+fn stored_size(task: &Task) -> Option<usize> {
+    match task {
+        Task::Set(key, data)
+        | Task::SetWithTtl(key, data, _)
+        | Task::SetIfAbsent(key, data)
+        | Task::CompareAndSwap(key, _, data) => Some(key.len() + data.len()),
+        Task::SetBytes(key, data) | Task::SetBytesWithTtl(key, data, _) => {
+            Some(key.len() + data.len())
+        }
+        _ => None,
+    }
+}
+
+/// Values and file descriptors stored in one namespace.
+#[derive(Debug, Default)]
+pub struct Namespace {
+    values: HashMap<String, Entry>,
+    descriptors: HashMap<String, OwnedFd>,
+    // total size of the values
+    bytes: usize,
+}
+
+impl Namespace {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values
+            .get(key)
+            .filter(|entry| !entry.is_expired(SystemTime::now()))
+            .map(|entry| &entry.value)
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        self.values
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len() + self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the keys and values stored.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn insert(&mut self, key: &str, value: Value, ttl: Option<Duration>) {
+        let entry = Entry::new(value, ttl);
+        self.bytes += entry.size(key);
+        if let Some(old) = self.values.insert(key.into(), entry) {
+            self.bytes -= old.size(key);
+        }
+    }
+
+    /// Replace the value and keep the expiry of the existing entry.
+    fn update(&mut self, key: &str, value: Value) {
+        match self.values.get_mut(key) {
+            Some(entry) => {
+                self.bytes = self.bytes - entry.value.len() + value.len();
+                entry.value = value;
+            }
+            None => self.insert(key, value, None),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.values.remove(key)?;
+        self.bytes -= entry.size(key);
+        Some(entry)
+    }
+
+    /// Keep the values matching the predicate, returns how many were removed.
+    fn retain<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&str, &Entry) -> bool,
+    {
+        let before = self.values.len();
+        let mut freed = 0;
+        self.values.retain(|key, entry| {
+            let kept = keep(key, entry);
+            if !kept {
+                freed += entry.size(key);
+            }
+            kept
+        });
+        self.bytes -= freed;
+        before - self.values.len()
+    }
+
+    /// Remove expired values, returns how many were removed.
+    fn sweep(&mut self, now: SystemTime) -> usize {
+        self.retain(|_, entry| !entry.is_expired(now))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    namespaces: HashMap<String, Namespace>,
+    // values changed since the last save
+    dirty: bool,
+    capacity: Capacity,
+    // least recently used first, may hold stale positions of values used
+    // again or removed since
+    lru: BTreeMap<u64, (String, String)>,
+    tick: u64,
+    evictions: u64,
+    expirations: u64,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: Capacity) -> Self {
+        let mut state = Self::new();
+        state.set_capacity(capacity);
+        state
+    }
+
+    /// Change the limits, evicting values that no longer fit.
+    pub fn set_capacity(&mut self, capacity: Capacity) {
+        self.capacity = capacity;
+        self.reindex();
+        self.evict();
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        self.capacity
+    }
+
+    /// Number of values stored in all namespaces.
+    pub fn entries(&self) -> usize {
+        self.namespaces
+            .values()
+            .map(|namespace| namespace.values.len())
+            .sum()
+    }
+
+    /// Total size of the keys and values stored in all namespaces.
+    pub fn bytes(&self) -> usize {
+        self.namespaces.values().map(Namespace::bytes).sum()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            namespaces: self.namespaces.len() as u64,
+            entries: self.entries() as u64,
+            bytes: self.bytes() as u64,
+            max_entries: self.capacity.max_entries.map(|max| max as u64),
+            max_bytes: self.capacity.max_bytes.map(|max| max as u64),
+            evictions: self.evictions,
+            expirations: self.expirations,
+        }
+    }
+
+    /// Load values from a snapshot file, a missing file gives an empty state.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err),
+        };
+
+        let snapshot: HashMap<String, HashMap<String, Entry>> = bincode::deserialize(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let namespaces = snapshot
+            .into_iter()
+            .map(|(name, values)| {
+                let bytes = values.iter().map(|(key, entry)| entry.size(key)).sum();
+                let namespace = Namespace {
+                    values,
+                    descriptors: HashMap::new(),
+                    bytes,
+                };
+                (name, namespace)
+            })
+            .collect();
+
+        Ok(Self {
+            namespaces,
+            ..Self::default()
+        })
+    }
+
+    /// Write values to a snapshot file.
+    ///
+    /// The snapshot is written next to the file and renamed over it, so a
+    /// crash never leaves a truncated snapshot behind.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        let snapshot: HashMap<&String, &HashMap<String, Entry>> = self
+            .namespaces
+            .iter()
+            .filter(|(_, namespace)| !namespace.values.is_empty())
+            .map(|(name, namespace)| (name, &namespace.values))
+            .collect();
+
+        let bytes = bincode::serialize(&snapshot)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Whether values changed since the state was loaded or saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Remove expired values from all namespaces, returns how many were
+    /// removed.
+    pub fn sweep(&mut self) -> usize {
+        let now = SystemTime::now();
+        let removed: usize = self
+            .namespaces
+            .values_mut()
+            .map(|namespace| namespace.sweep(now))
+            .sum();
+
+        self.namespaces.retain(|_, namespace| !namespace.is_empty());
+        self.dirty |= removed > 0;
+        self.expirations += removed as u64;
+        removed
+    }
+
+    /// Move the value to the most recently used end of the LRU order.
+    fn mark_used(&mut self, name: &str, key: &str) {
+        let entry = self
+            .namespaces
+            .get_mut(name)
+            .and_then(|namespace| namespace.values.get_mut(key));
+        if let Some(entry) = entry {
+            self.tick += 1;
+            entry.used = self.tick;
+            self.lru.insert(self.tick, (name.into(), key.into()));
+        }
+    }
+
+    /// Rebuild the LRU order from the values, dropping stale positions.
+    fn reindex(&mut self) {
+        self.lru.clear();
+        self.tick = 0;
+        if !self.capacity.is_limited() {
+            return;
+        }
+
+        let mut used: Vec<(u64, String, String)> = self
+            .namespaces
+            .iter()
+            .flat_map(|(name, namespace)| {
+                namespace
+                    .values
+                    .iter()
+                    .map(move |(key, entry)| (entry.used, name.clone(), key.clone()))
+            })
+            .collect();
+        used.sort();
+
+        for (_, name, key) in used {
+            self.mark_used(&name, &key);
+        }
+    }
+
+    fn over_capacity(&self) -> bool {
+        self.capacity
+            .max_entries
+            .is_some_and(|max| self.entries() > max)
+            || self
+                .capacity
+                .max_bytes
+                .is_some_and(|max| self.bytes() > max)
+    }
+
+    /// Evict least recently used values until they fit the capacity.
+    fn evict(&mut self) {
+        if !self.capacity.is_limited() {
+            return;
+        }
+
+        while self.over_capacity() {
+            let (tick, (name, key)) = match self.lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let namespace = match self.namespaces.get_mut(&name) {
+                Some(namespace) => namespace,
+                None => continue,
+            };
+            // skip positions of values used again or removed since
+            if namespace
+                .values
+                .get(&key)
+                .is_some_and(|entry| entry.used == tick)
+            {
+                #[cfg(feature = "log")]
+                log::info!("evict {}", key);
+                namespace.remove(&key);
+                self.evictions += 1;
+                self.dirty = true;
+                if namespace.is_empty() {
+                    self.namespaces.remove(&name);
+                }
+            }
+        }
+
+        // do not let stale positions grow the order without bound
+        if self.lru.len() > 2 * self.entries() + 64 {
+            self.reindex();
+        }
+    }
+
+    pub fn namespace(&self, name: &str) -> Option<&Namespace> {
+        self.namespaces.get(name)
+    }
+
+    /// Names of the namespaces holding any data.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces
+            .iter()
+            .filter(|(_, namespace)| !namespace.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Apply a built-in task, handlers use this to work with stored values.
+    ///
+    /// File descriptors can not be passed this way, [`Task::GetFd`] only
+    /// reports whether one is stored.
+    pub fn apply(&mut self, task: Task) -> Response {
+        self.handle(task, Vec::new()).0
+    }
+
+    /// Apply a task and return the response along with a file descriptor to
+    /// attach to it.
+    pub(crate) fn handle(&mut self, task: Task, fds: Vec<OwnedFd>) -> (Response, Option<RawFd>) {
+        match task {
+            Task::Namespaced(name, task) => match *task {
+                Task::Namespaced(..) => {
+                    (Response::Error("namespaces can not be nested".into()), None)
+                }
+                task => self.handle_in(&name, task, fds),
+            },
+            task => self.handle_in(DEFAULT_NAMESPACE, task, fds),
+        }
+    }
+
+    fn handle_in(
+        &mut self,
+        name: &str,
+        task: Task,
+        mut fds: Vec<OwnedFd>,
+    ) -> (Response, Option<RawFd>) {
+        let writes = matches!(
+            task,
+            Task::Set(..)
+                | Task::SetBytes(..)
+                | Task::SetWithTtl(..)
+                | Task::SetBytesWithTtl(..)
+                | Task::Touch(..)
+                | Task::Incr(..)
+                | Task::CompareAndSwap(..)
+                | Task::SetIfAbsent(..)
+                | Task::Delete(..)
+                | Task::DeletePrefix(..)
+                | Task::Clear
+        );
+
+        let key = task.key().map(str::to_owned);
+
+        // a value that can not fit must not evict the others
+        if let Some(size) = stored_size(&task) {
+            if let Some(max) = self.capacity.max_bytes.filter(|max| size > *max) {
+                let message = format!("{size} bytes exceed the capacity of {max} bytes");
+                return (Response::Error(message), None);
+            }
+        }
+
+        let namespace = self.namespaces.entry(name.into()).or_default();
+
+        // expired values behave as if they were never stored
+        let now = SystemTime::now();
+        let expired = match &key {
+            Some(key) => {
+                let expired = namespace
+                    .values
+                    .get(key)
+                    .is_some_and(|entry| entry.is_expired(now));
+                if expired {
+                    namespace.remove(key);
+                }
+                expired as usize
+            }
+            None => namespace.sweep(now),
+        };
+
+        let response = match task {
+            Task::Get(key) => {
+                #[cfg(feature = "log")]
+                log::info!("get {}", key);
+                match namespace.values.get(&*key) {
+                    Some(entry) => entry.value.clone().into(),
+                    None => Response::NotFound,
+                }
+            }
+            Task::Set(key, data) => {
+                #[cfg(feature = "log")]
+                log::info!("set {}", key);
+                namespace.insert(&key, Value::String(data.into()), None);
+                Response::Ok
+            }
+            Task::SetBytes(key, data) => {
+                #[cfg(feature = "log")]
+                log::info!("set bytes {}", key);
+                namespace.insert(&key, Value::Bytes(data), None);
+                Response::Ok
+            }
+            Task::SetWithTtl(key, data, ttl) => {
+                #[cfg(feature = "log")]
+                log::info!("set {} for {:?}", key, ttl);
+                namespace.insert(&key, Value::String(data.into()), Some(ttl));
+                Response::Ok
+            }
+            Task::SetBytesWithTtl(key, data, ttl) => {
+                #[cfg(feature = "log")]
+                log::info!("set bytes {} for {:?}", key, ttl);
+                namespace.insert(&key, Value::Bytes(data), Some(ttl));
+                Response::Ok
+            }
+            Task::Touch(key, ttl) => {
+                #[cfg(feature = "log")]
+                log::info!("touch {} for {:?}", key, ttl);
+                match namespace.values.get_mut(&*key) {
+                    Some(entry) => {
+                        entry.expires = expiry(now, ttl);
+                        Response::Bool(true)
+                    }
+                    None => Response::Bool(false),
+                }
+            }
+            Task::List => Response::List(namespace.keys()),
+            Task::Sum(values) => values
+                .iter()
+                .try_fold(0i64, |acc, value| acc.checked_add(*value))
+                .map_or(Response::Overflow, Response::Integer),
+            Task::Min(values) => values
+                .into_iter()
+                .min()
+                .map_or(Response::NotFound, Response::Integer),
+            Task::Max(values) => values
+                .into_iter()
+                .max()
+                .map_or(Response::NotFound, Response::Integer),
+            Task::Mean(values) => {
+                if values.is_empty() {
+                    Response::NotFound
+                } else {
+                    let sum: f64 = values.iter().map(|value| *value as f64).sum();
+                    Response::Float(sum / values.len() as f64)
+                }
+            }
+            Task::Incr(key, delta) => {
+                #[cfg(feature = "log")]
+                log::info!("incr {}", key);
+                let current = match namespace.values.get(&*key) {
+                    Some(entry) => entry
+                        .value
+                        .as_str()
+                        .and_then(|data| data.parse::<i64>().ok()),
+                    None => Some(0),
+                };
+                match current {
+                    Some(current) => match current.checked_add(delta) {
+                        Some(value) => {
+                            namespace.update(&key, Value::String(value.to_string()));
+                            Response::Integer(value)
+                        }
+                        None => Response::Overflow,
+                    },
+                    None => Response::Error(format!("value of {key} is not an integer")),
+                }
+            }
+            Task::CompareAndSwap(key, expected, data) => {
+                #[cfg(feature = "log")]
+                log::info!("compare and swap {}", key);
+                let current = namespace
+                    .values
+                    .get(&*key)
+                    .map(|entry| entry.value.as_str());
+                let swapped = current == expected.as_deref().map(Some);
+                if swapped {
+                    namespace.update(&key, Value::String(data.into()));
+                }
+                Response::Bool(swapped)
+            }
+            Task::SetIfAbsent(key, data) => {
+                #[cfg(feature = "log")]
+                log::info!("set if absent {}", key);
+                let absent = !namespace.values.contains_key(&*key);
+                if absent {
+                    namespace.insert(&key, Value::String(data.into()), None);
+                }
+                Response::Bool(absent)
+            }
+            Task::Delete(key) => {
+                #[cfg(feature = "log")]
+                log::info!("delete {}", key);
+                let removed = namespace.remove(&key).is_some() as u64
+                    + namespace.descriptors.remove(&*key).is_some() as u64;
+                Response::Count(removed)
+            }
+            Task::DeletePrefix(prefix) => {
+                #[cfg(feature = "log")]
+                log::info!("delete prefix {}", prefix);
+                let before = namespace.descriptors.len();
+                namespace
+                    .descriptors
+                    .retain(|key, _| !key.starts_with(&*prefix));
+                let removed = namespace.retain(|key, _| !key.starts_with(&*prefix)) + before
+                    - namespace.descriptors.len();
+                Response::Count(removed as u64)
+            }
+            Task::Clear => {
+                #[cfg(feature = "log")]
+                log::info!("clear");
+                let removed = namespace.len();
+                *namespace = Namespace::default();
+                Response::Count(removed as u64)
+            }
+            Task::SetFd(key) => {
+                #[cfg(feature = "log")]
+                log::info!("set fd {}", key);
+                match fds.pop() {
+                    Some(fd) => {
+                        namespace.descriptors.insert(key.into(), fd);
+                        Response::Ok
+                    }
+                    None => Response::Error("no file descriptor attached".into()),
+                }
+            }
+            Task::GetFd(key) => {
+                #[cfg(feature = "log")]
+                log::info!("get fd {}", key);
+                if let Some(fd) = namespace.descriptors.get(&*key) {
+                    return (Response::Fd, Some(fd.as_raw_fd()));
+                }
+                Response::NotFound
+            }
+            Task::Namespaces => Response::List(self.namespaces()),
+            Task::Stats => Response::Stats(self.stats()),
+            Task::Namespaced(..) => unreachable!("unwrapped by State::handle"),
+            Task::Shutdown => Response::Error("shutdown is handled by the server".into()),
+            Task::Hello { .. } => Response::Error("hello is handled by the server".into()),
+            Task::Call(..) => Response::Error("calls are handled by the server".into()),
+        };
+
+        self.dirty |= writes || expired > 0;
+        self.expirations += expired as u64;
+
+        if let Some(key) = key {
+            if self.capacity.is_limited() {
+                self.mark_used(name, &key);
+            }
+        }
+        self.evict();
+
+        // do not keep namespaces around once everything is removed from them
+        if self.namespaces.get(name).is_some_and(Namespace::is_empty) {
+            self.namespaces.remove(name);
+        }
+
+        (response, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    fn get(state: &mut State, key: &str) -> Response {
+        state.apply(Task::Get(key.into()))
+    }
+
+    #[test]
+    fn values_expire_after_their_ttl() {
+        let mut state = State::new();
+        let ttl = Duration::from_millis(20);
+        state.apply(Task::SetWithTtl("a".into(), "1".into(), ttl));
+        state.apply(Task::Set("b".into(), "2".into()));
+        assert!(matches!(get(&mut state, "a"), Response::String(value) if value == "1"));
+
+        thread::sleep(2 * ttl);
+        assert!(matches!(get(&mut state, "a"), Response::NotFound));
+        assert!(matches!(get(&mut state, "b"), Response::String(value) if value == "2"));
+        assert_eq!(state.stats().expirations, 1);
+    }
+
+    #[test]
+    fn touch_extends_the_ttl() {
+        let mut state = State::new();
+        let ttl = Duration::from_millis(20);
+        state.apply(Task::SetWithTtl("a".into(), "1".into(), ttl));
+        let touched = state.apply(Task::Touch("a".into(), Duration::from_secs(60)));
+        assert!(matches!(touched, Response::Bool(true)));
+
+        thread::sleep(2 * ttl);
+        assert!(matches!(get(&mut state, "a"), Response::String(_)));
+        let missing = state.apply(Task::Touch("b".into(), ttl));
+        assert!(matches!(missing, Response::Bool(false)));
+    }
+
+    #[test]
+    fn unrepresentable_ttl_never_expires() {
+        let mut state = State::new();
+        let stored = state.apply(Task::SetWithTtl("a".into(), "1".into(), Duration::MAX));
+        assert!(matches!(stored, Response::Ok));
+        let touched = state.apply(Task::Touch("a".into(), Duration::MAX));
+        assert!(matches!(touched, Response::Bool(true)));
+        assert_eq!(state.sweep(), 0);
+        assert!(matches!(get(&mut state, "a"), Response::String(_)));
+    }
+
+    fn limited(max_entries: Option<usize>, max_bytes: Option<usize>) -> State {
+        State::with_capacity(Capacity {
+            max_entries,
+            max_bytes,
+        })
+    }
+
+    #[test]
+    fn bytes_count_keys_and_values() {
+        let mut state = State::new();
+        state.apply(Task::Set("a".into(), "123".into()));
+        state.apply(Task::SetBytes("bc".into(), vec![0; 10]));
+        state.apply(Task::Set("a".into(), "1".into()).namespaced("other"));
+        assert_eq!(state.bytes(), 4 + 12 + 2);
+
+        state.apply(Task::Set("a".into(), "12345".into()));
+        assert_eq!(state.bytes(), 6 + 12 + 2);
+        state.apply(Task::Delete("bc".into()));
+        state.apply(Task::Clear.namespaced("other"));
+        assert_eq!(state.bytes(), 6);
+        assert_eq!(state.stats().namespaces, 1);
+    }
+
+    #[test]
+    fn least_recently_used_values_are_evicted_first() {
+        let mut state = limited(Some(2), None);
+        state.apply(Task::Set("a".into(), "1".into()));
+        state.apply(Task::Set("b".into(), "2".into()));
+        get(&mut state, "a");
+        state.apply(Task::Set("c".into(), "3".into()));
+
+        assert!(matches!(get(&mut state, "b"), Response::NotFound));
+        assert!(matches!(get(&mut state, "a"), Response::String(_)));
+        assert!(matches!(get(&mut state, "c"), Response::String(_)));
+        assert_eq!(state.stats().evictions, 1);
+    }
+
+    #[test]
+    fn eviction_keeps_within_max_bytes() {
+        let mut state = limited(None, Some(10));
+        state.apply(Task::Set("a".into(), "1234".into()));
+        state.apply(Task::Set("b".into(), "1234".into()));
+        state.apply(Task::Set("c".into(), "1234".into()));
+
+        assert!(state.bytes() <= 10);
+        assert!(matches!(get(&mut state, "a"), Response::NotFound));
+        assert!(matches!(get(&mut state, "c"), Response::String(_)));
+        assert_eq!(state.stats().evictions, 1);
+    }
+
+    #[test]
+    fn values_larger_than_the_capacity_are_rejected() {
+        let mut state = limited(None, Some(100));
+        state.apply(Task::Set("a".into(), "1".into()));
+        state.apply(Task::Set("b".into(), "2".into()).namespaced("other"));
+
+        let stored = state.apply(Task::Set("c".into(), "x".repeat(200).into()));
+        assert!(matches!(stored, Response::Error(_)));
+        let stats = state.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 0));
+    }
+
+    #[test]
+    fn incr_adds_to_integers() {
+        let mut state = State::new();
+        assert!(matches!(
+            state.apply(Task::Incr("n".into(), 2)),
+            Response::Integer(2)
+        ));
+        assert!(matches!(
+            state.apply(Task::Incr("n".into(), -5)),
+            Response::Integer(-3)
+        ));
+
+        state.apply(Task::Set("max".into(), i64::MAX.to_string().into()));
+        let overflow = state.apply(Task::Incr("max".into(), 1));
+        assert!(matches!(overflow, Response::Overflow));
+        assert!(
+            matches!(get(&mut state, "max"), Response::String(value) if value == i64::MAX.to_string())
+        );
+
+        state.apply(Task::Set("word".into(), "one".into()));
+        let word = state.apply(Task::Incr("word".into(), 1));
+        assert!(matches!(word, Response::Error(_)));
+        assert!(matches!(get(&mut state, "word"), Response::String(value) if value == "one"));
+    }
+
+    #[test]
+    fn compare_and_swap_replaces_expected_values() {
+        let mut state = State::new();
+        let absent = state.apply(Task::CompareAndSwap("a".into(), None, "1".into()));
+        assert!(matches!(absent, Response::Bool(true)));
+        let taken = state.apply(Task::CompareAndSwap("a".into(), None, "2".into()));
+        assert!(matches!(taken, Response::Bool(false)));
+
+        let stale = state.apply(Task::CompareAndSwap(
+            "a".into(),
+            Some("0".into()),
+            "2".into(),
+        ));
+        assert!(matches!(stale, Response::Bool(false)));
+        let current = state.apply(Task::CompareAndSwap(
+            "a".into(),
+            Some("1".into()),
+            "2".into(),
+        ));
+        assert!(matches!(current, Response::Bool(true)));
+        assert!(matches!(get(&mut state, "a"), Response::String(value) if value == "2"));
+    }
+
+    #[test]
+    fn set_if_absent_keeps_stored_values() {
+        let mut state = State::new();
+        let stored = state.apply(Task::SetIfAbsent("a".into(), "1".into()));
+        assert!(matches!(stored, Response::Bool(true)));
+        let kept = state.apply(Task::SetIfAbsent("a".into(), "2".into()));
+        assert!(matches!(kept, Response::Bool(false)));
+        assert!(matches!(get(&mut state, "a"), Response::String(value) if value == "1"));
+    }
+
+    #[test]
+    fn failed_writes_evict_nothing() {
+        let mut state = limited(Some(2), None);
+        state.apply(Task::Set("a".into(), "1".into()));
+        state.apply(Task::Set("b".into(), "2".into()));
+
+        let swapped = state.apply(Task::CompareAndSwap(
+            "c".into(),
+            Some("x".into()),
+            "3".into(),
+        ));
+        assert!(matches!(swapped, Response::Bool(false)));
+        let stored = state.apply(Task::SetIfAbsent("b".into(), "3".into()));
+        assert!(matches!(stored, Response::Bool(false)));
+        assert!(matches!(get(&mut state, "a"), Response::String(value) if value == "1"));
+        assert_eq!(state.stats().evictions, 0);
+    }
+}
@@ -0,0 +1,789 @@
+//! Socket transports the companion listens on.
+//!
+//! The transport is chosen by the scheme of the `RUST_COMPANION` address:
+//!
+//! * `unix:/path/to/socket` - UNIX datagram socket at the given path
+//! * `unix:` - UNIX datagram socket in the per-user [`runtime_dir`]
+//! * `udp:host:port` or plain `host:port` - UDP socket
+//! * `unix-stream:/path/to/socket` or `unix-stream:` - UNIX stream socket
+//! * `tcp:host:port` - TCP socket
+//!
+//! Datagram transports limit messages to [`MAX_DATAGRAM`] bytes. Stream
+//! transports carry messages of any size, each prefixed with its length as a
+//! big endian `u32`.
+//!
+//! File descriptors can be attached to messages sent over UNIX sockets, they
+//! are transferred with `SCM_RIGHTS` and arrive as new descriptors owned by
+//! the receiver.
+//!
+use std::{
+    fmt, fs,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt},
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixDatagram, UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+
+use crate::PROGRAM_NAME;
+
+const UNIX_SCHEME: &str = "unix:";
+const UDP_SCHEME: &str = "udp:";
+const UNIX_STREAM_SCHEME: &str = "unix-stream:";
+const TCP_SCHEME: &str = "tcp:";
+
+/// Largest payload that fits in a single UDP datagram.
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// Maximum number of file descriptors attached to a single datagram.
+pub const MAX_FDS: usize = 16;
+
+/// Address of the companion parsed from `companion_addr()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Udp(String),
+    Unix(PathBuf),
+    Tcp(String),
+    UnixStream(PathBuf),
+}
+
+impl Address {
+    pub fn parse(addr: &str) -> Self {
+        let unix_path = |path: &str| {
+            if path.is_empty() {
+                socket_path()
+            } else {
+                path.into()
+            }
+        };
+
+        if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+            Address::Unix(unix_path(path))
+        } else if let Some(path) = addr.strip_prefix(UNIX_STREAM_SCHEME) {
+            Address::UnixStream(unix_path(path))
+        } else if let Some(addr) = addr.strip_prefix(TCP_SCHEME) {
+            Address::Tcp(addr.into())
+        } else if let Some(addr) = addr.strip_prefix(UDP_SCHEME) {
+            Address::Udp(addr.into())
+        } else {
+            Address::Udp(addr.into())
+        }
+    }
+
+    /// Whether the transport carries length-prefixed messages over a stream.
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Address::Tcp(_) | Address::UnixStream(_))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Udp(addr) => write!(f, "{UDP_SCHEME}{addr}"),
+            Address::Unix(path) => write!(f, "{UNIX_SCHEME}{}", path.display()),
+            Address::Tcp(addr) => write!(f, "{TCP_SCHEME}{addr}"),
+            Address::UnixStream(path) => write!(f, "{UNIX_STREAM_SCHEME}{}", path.display()),
+        }
+    }
+}
+
+/// Per-user directory for socket files.
+///
+/// Uses `$XDG_RUNTIME_DIR` when set, otherwise a `rust-companion-<uid>`
+/// directory in the system temp dir that only the owner can access. Sockets
+/// are not bound in that directory when somebody else can access it.
+pub fn runtime_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return dir.into();
+    }
+    fallback_runtime_dir()
+}
+
+fn fallback_runtime_dir() -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("{}-{}", PROGRAM_NAME, nix::unistd::getuid()));
+    dir
+}
+
+/// Default path of the companion UNIX socket.
+pub fn socket_path() -> PathBuf {
+    let mut path = runtime_dir();
+    path.push(format!("{}.sock", PROGRAM_NAME));
+    path
+}
+
+/// Sender of a datagram, used to route the reply back.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Udp(SocketAddr),
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Udp(addr) => write!(f, "{addr}"),
+            Peer::Unix(Some(path)) => write!(f, "{}", path.display()),
+            Peer::Unix(None) => write!(f, "(unnamed)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Inner {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Datagram socket over one of the supported transports.
+///
+/// UNIX sockets remove their socket file when dropped.
+#[derive(Debug)]
+pub struct Socket {
+    inner: Inner,
+    path: Option<PathBuf>,
+}
+
+impl Socket {
+    /// Bind the companion side of the transport.
+    pub fn bind(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Udp(addr) => Ok(Self {
+                inner: Inner::Udp(UdpSocket::bind(addr)?),
+                path: None,
+            }),
+            Address::Unix(path) => {
+                prepare_socket_path(path)?;
+                Ok(Self {
+                    inner: Inner::Unix(UnixDatagram::bind(path)?),
+                    path: Some(path.clone()),
+                })
+            }
+            _ => Err(not_datagram(addr)),
+        }
+    }
+
+    /// Bind a client socket and connect it to the companion.
+    pub fn connect(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Udp(addr) => {
+                let sock = UdpSocket::bind("[::]:0")?;
+                sock.connect(addr)?;
+                Ok(Self {
+                    inner: Inner::Udp(sock),
+                    path: None,
+                })
+            }
+            Address::Unix(path) => {
+                // datagram replies need a named socket to be routed back
+                let local = client_socket_path();
+                prepare_socket_path(&local)?;
+                let sock = UnixDatagram::bind(&local)?;
+                let sock = Self {
+                    inner: Inner::Unix(sock),
+                    path: Some(local),
+                };
+                if let Inner::Unix(ref inner) = sock.inner {
+                    inner.connect(path)?;
+                }
+                Ok(sock)
+            }
+            _ => Err(not_datagram(addr)),
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Peer)> {
+        match &self.inner {
+            Inner::Udp(sock) => {
+                let (len, src) = sock.recv_from(buf)?;
+                Ok((len, Peer::Udp(src)))
+            }
+            Inner::Unix(sock) => {
+                let (len, src) = sock.recv_from(buf)?;
+                Ok((len, Peer::Unix(src.as_pathname().map(Path::to_path_buf))))
+            }
+        }
+    }
+
+    pub fn send_to(&self, buf: &[u8], peer: &Peer) -> io::Result<usize> {
+        match (&self.inner, peer) {
+            (Inner::Udp(sock), Peer::Udp(addr)) => sock.send_to(buf, addr),
+            (Inner::Unix(sock), Peer::Unix(Some(path))) => sock.send_to(buf, path),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can not reply to peer {peer}"),
+            )),
+        }
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match &self.inner {
+            Inner::Udp(sock) => sock.send(buf),
+            Inner::Unix(sock) => sock.send(buf),
+        }
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.inner {
+            Inner::Udp(sock) => sock.recv(buf),
+            Inner::Unix(sock) => sock.recv(buf),
+        }
+    }
+
+    /// Send a datagram with file descriptors attached to the connected peer.
+    pub fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if fds.is_empty() {
+            return self.send(buf);
+        }
+        self.sendmsg(buf, fds, None)
+    }
+
+    /// Send a datagram with file descriptors attached to `peer`.
+    pub fn send_to_with_fds(&self, buf: &[u8], fds: &[RawFd], peer: &Peer) -> io::Result<usize> {
+        if fds.is_empty() {
+            return self.send_to(buf, peer);
+        }
+        match peer {
+            Peer::Unix(Some(path)) => self.sendmsg(buf, fds, Some(&UnixAddr::new(path.as_path())?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can not reply to peer {peer}"),
+            )),
+        }
+    }
+
+    /// Receive a datagram from the connected peer along with any attached
+    /// file descriptors.
+    pub fn recv_with_fds(&self, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+        let (len, _, fds) = self.recv_from_with_fds(buf)?;
+        Ok((len, fds))
+    }
+
+    /// Receive a datagram along with any attached file descriptors.
+    ///
+    /// Transports without descriptor passing always return an empty list.
+    pub fn recv_from_with_fds(&self, buf: &mut [u8]) -> io::Result<(usize, Peer, Vec<OwnedFd>)> {
+        let sock = match &self.inner {
+            Inner::Unix(sock) => sock,
+            Inner::Udp(_) => {
+                let (len, peer) = self.recv_from(buf)?;
+                return Ok((len, peer, Vec::new()));
+            }
+        };
+
+        let mut iov = [IoSliceMut::new(buf)];
+        let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS]);
+        let msg = recvmsg::<UnixAddr>(
+            sock.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: the kernel installed these descriptors for us
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+
+        if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("more than {MAX_FDS} file descriptors attached"),
+            ));
+        }
+
+        let peer = Peer::Unix(
+            msg.address
+                .and_then(|addr| addr.path().map(Path::to_path_buf)),
+        );
+        Ok((msg.bytes, peer, fds))
+    }
+
+    fn sendmsg(&self, buf: &[u8], fds: &[RawFd], addr: Option<&UnixAddr>) -> io::Result<usize> {
+        let sock = match &self.inner {
+            Inner::Unix(sock) => sock,
+            Inner::Udp(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "file descriptors can only be passed over UNIX sockets",
+                ))
+            }
+        };
+
+        let iov = [IoSlice::new(buf)];
+        let cmsgs = [ControlMessage::ScmRights(fds)];
+        Ok(sendmsg(
+            sock.as_raw_fd(),
+            &iov,
+            &cmsgs,
+            MsgFlags::empty(),
+            addr,
+        )?)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.inner {
+            Inner::Udp(sock) => sock.set_read_timeout(timeout),
+            Inner::Unix(sock) => sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.inner {
+            Inner::Udp(sock) => sock.set_write_timeout(timeout),
+            Inner::Unix(sock) => sock.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum StreamInner {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Connected stream carrying length-prefixed messages.
+#[derive(Debug)]
+pub struct Stream {
+    inner: StreamInner,
+}
+
+impl Stream {
+    /// Connect to the companion over a stream transport.
+    pub fn connect(addr: &Address) -> io::Result<Self> {
+        let inner = match addr {
+            Address::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                StreamInner::Tcp(stream)
+            }
+            Address::UnixStream(path) => StreamInner::Unix(UnixStream::connect(path)?),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{addr} is not a stream transport"),
+                ))
+            }
+        };
+        Ok(Self { inner })
+    }
+
+    /// Send one message, file descriptors can only be attached over UNIX
+    /// streams.
+    pub fn send_message(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+        let len = u32::try_from(buf.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message does not fit in a frame",
+            )
+        })?;
+
+        let mut frame = Vec::with_capacity(4 + buf.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(buf);
+
+        match &self.inner {
+            StreamInner::Tcp(_) if !fds.is_empty() => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "file descriptors can only be passed over UNIX sockets",
+            )),
+            StreamInner::Unix(stream) if !fds.is_empty() => {
+                // descriptors travel with the first chunk of the frame
+                let iov = [IoSlice::new(&frame)];
+                let cmsgs = [ControlMessage::ScmRights(fds)];
+                let sent =
+                    sendmsg::<UnixAddr>(stream.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+                (&*stream).write_all(&frame[sent..])
+            }
+            StreamInner::Tcp(stream) => (&*stream).write_all(&frame),
+            StreamInner::Unix(stream) => (&*stream).write_all(&frame),
+        }
+    }
+
+    /// Receive one message along with any attached file descriptors.
+    pub fn recv_message(&self) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+        let mut header = [0; 4];
+        let mut fds = Vec::new();
+
+        match &self.inner {
+            StreamInner::Tcp(stream) => (&*stream).read_exact(&mut header)?,
+            StreamInner::Unix(stream) => {
+                let mut read = 0;
+                while read < header.len() {
+                    let mut iov = [IoSliceMut::new(&mut header[read..])];
+                    let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS]);
+                    let msg = recvmsg::<UnixAddr>(
+                        stream.as_raw_fd(),
+                        &mut iov,
+                        Some(&mut cmsg),
+                        MsgFlags::MSG_CMSG_CLOEXEC,
+                    )?;
+                    if msg.bytes == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    for cmsg in msg.cmsgs() {
+                        if let ControlMessageOwned::ScmRights(received) = cmsg {
+                            // SAFETY: the kernel installed these descriptors for us
+                            fds.extend(
+                                received
+                                    .into_iter()
+                                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                            );
+                        }
+                    }
+                    read += msg.bytes;
+                }
+            }
+        }
+
+        let len = u32::from_be_bytes(header) as u64;
+        let mut buf = Vec::new();
+        match &self.inner {
+            StreamInner::Tcp(stream) => stream.take(len).read_to_end(&mut buf)?,
+            StreamInner::Unix(stream) => stream.take(len).read_to_end(&mut buf)?,
+        };
+        if (buf.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok((buf, fds))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.inner {
+            StreamInner::Tcp(stream) => stream.set_read_timeout(timeout),
+            StreamInner::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.inner {
+            StreamInner::Tcp(stream) => stream.set_write_timeout(timeout),
+            StreamInner::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.inner {
+            StreamInner::Tcp(stream) => stream.as_raw_fd(),
+            StreamInner::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ListenerInner {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Companion side of a stream transport.
+///
+/// UNIX listeners remove their socket file when dropped.
+#[derive(Debug)]
+pub struct Listener {
+    inner: ListenerInner,
+    path: Option<PathBuf>,
+}
+
+impl Listener {
+    pub fn bind(addr: &Address) -> io::Result<Self> {
+        match addr {
+            Address::Tcp(addr) => Ok(Self {
+                inner: ListenerInner::Tcp(TcpListener::bind(addr)?),
+                path: None,
+            }),
+            Address::UnixStream(path) => {
+                prepare_socket_path(path)?;
+                Ok(Self {
+                    inner: ListenerInner::Unix(UnixListener::bind(path)?),
+                    path: Some(path.clone()),
+                })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{addr} is not a stream transport"),
+            )),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        let inner = match &self.inner {
+            ListenerInner::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                StreamInner::Tcp(stream)
+            }
+            ListenerInner::Unix(listener) => StreamInner::Unix(listener.accept()?.0),
+        };
+        Ok(Stream { inner })
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.inner {
+            ListenerInner::Tcp(listener) => listener.as_raw_fd(),
+            ListenerInner::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Client side of any transport, carrying whole messages.
+#[derive(Debug)]
+pub enum Connection {
+    Datagram(Socket),
+    Stream(Stream),
+}
+
+impl Connection {
+    pub fn connect(addr: &Address) -> io::Result<Self> {
+        if addr.is_stream() {
+            Ok(Connection::Stream(Stream::connect(addr)?))
+        } else {
+            Ok(Connection::Datagram(Socket::connect(addr)?))
+        }
+    }
+
+    pub fn send(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+        match self {
+            Connection::Datagram(_) if buf.len() > MAX_DATAGRAM => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message does not fit in a datagram, use a stream transport",
+            )),
+            Connection::Datagram(sock) => sock.send_with_fds(buf, fds).map(|_| ()),
+            Connection::Stream(stream) => stream.send_message(buf, fds),
+        }
+    }
+
+    pub fn recv(&self) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+        match self {
+            Connection::Datagram(sock) => {
+                let mut buf = vec![0; MAX_DATAGRAM];
+                let (len, fds) = sock.recv_with_fds(&mut buf)?;
+                buf.truncate(len);
+                Ok((buf, fds))
+            }
+            Connection::Stream(stream) => stream.recv_message(),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Datagram(sock) => {
+                sock.set_read_timeout(timeout)?;
+                sock.set_write_timeout(timeout)
+            }
+            Connection::Stream(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+}
+
+fn not_datagram(addr: &Address) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{addr} is not a datagram transport"),
+    )
+}
+
+fn client_socket_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut path = runtime_dir();
+    path.push(format!(
+        "{}-{}-{}.sock",
+        PROGRAM_NAME,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path
+}
+
+/// Create the parent directory and remove a stale socket file left behind.
+fn prepare_socket_path(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        if parent == fallback_runtime_dir() {
+            // shared temp dir, anybody could have created it
+            private_dir(parent)?;
+        } else if !parent.exists() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+    }
+
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if is_listening(path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Create a directory only the current user can access, or check that the
+/// existing one is.
+fn private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not private to the current user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a socket is bound at the path, stale socket files refuse
+/// connections.
+fn is_listening(path: &Path) -> bool {
+    // connecting to a socket of the other kind fails with another error
+    match UnixDatagram::unbound().and_then(|sock| sock.connect(path)) {
+        Ok(()) => true,
+        Err(err) => !matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Seek, SeekFrom},
+        net::Shutdown,
+    };
+
+    use super::*;
+
+    fn unix(stream: UnixStream) -> Stream {
+        Stream {
+            inner: StreamInner::Unix(stream),
+        }
+    }
+
+    fn pair() -> (Stream, Stream) {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        (unix(sender), unix(receiver))
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (sender, receiver) = pair();
+        let large = vec![7; 4 * MAX_DATAGRAM];
+        let sending = std::thread::spawn(move || {
+            sender.send_message(b"", &[]).unwrap();
+            sender.send_message(&large, &[]).unwrap();
+        });
+        assert_eq!(receiver.recv_message().unwrap().0, b"");
+        assert_eq!(
+            receiver.recv_message().unwrap().0,
+            vec![7; 4 * MAX_DATAGRAM]
+        );
+        sending.join().unwrap();
+    }
+
+    #[test]
+    fn frames_carry_descriptors() {
+        let (sender, receiver) = pair();
+        let path = std::env::temp_dir().join(format!("companion-frame-{}", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        file.write_all(b"shared").unwrap();
+        sender.send_message(b"fd", &[file.as_raw_fd()]).unwrap();
+
+        let (message, mut fds) = receiver.recv_message().unwrap();
+        assert_eq!((message.as_slice(), fds.len()), (&b"fd"[..], 1));
+        let mut received = File::from(fds.remove(0));
+        received.seek(SeekFrom::Start(0)).unwrap();
+        let mut contents = String::new();
+        received.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "shared");
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        peer.write_all(&[0, 0]).unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        let err = unix(stream).recv_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        peer.write_all(&100u32.to_be_bytes()).unwrap();
+        peer.write_all(&[1; 10]).unwrap();
+        peer.shutdown(Shutdown::Write).unwrap();
+        let err = unix(stream).recv_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn descriptors_need_unix_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream {
+            inner: StreamInner::Tcp(TcpStream::connect(listener.local_addr().unwrap()).unwrap()),
+        };
+        let err = stream.send_message(b"fd", &[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}